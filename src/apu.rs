@@ -0,0 +1,1012 @@
+use std::f64::consts::PI;
+
+pub const CPU_CLOCK_HZ: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// Frame counter steps in CPU cycles
+const QUARTER_FRAME_STEPS: [usize; 4] = [7457, 14913, 22371, 29829];
+const FIVE_STEP_LAST: usize = 37281;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// DMC output clock periods in CPU cycles, NTSC
+const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// One-pole low-pass followed by decimation from the CPU clock down to the
+// host rate. The cutoff sits just under the host Nyquist frequency.
+struct Resampler {
+    cycles_per_sample: f64,
+    phase: f64,
+    alpha: f32,
+    filtered: f32,
+}
+
+impl Resampler {
+    fn new(sample_rate: u32) -> Self {
+        let cutoff: f64 = sample_rate as f64 * 0.45;
+        Resampler {
+            cycles_per_sample: CPU_CLOCK_HZ / sample_rate as f64,
+            phase: 0.0,
+            alpha: (1.0 - (-2.0 * PI * cutoff / CPU_CLOCK_HZ).exp()) as f32,
+            filtered: 0.0,
+        }
+    }
+
+    fn push(&mut self, input: f32, out: &mut Vec<f32>) {
+        self.filtered += self.alpha * (input - self.filtered);
+        self.phase += 1.0;
+        if self.phase >= self.cycles_per_sample {
+            self.phase -= self.cycles_per_sample;
+            out.push(self.filtered);
+        }
+    }
+}
+
+// Non-linear DAC mix of the channel outputs, pulses are 0-15 each,
+// triangle and noise 0-15, DMC 0-127. Result is in 0.0-1.0.
+pub fn mix(pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+    let pulse_sum: f32 = (pulse1 + pulse2) as f32;
+    let pulse_out: f32 = if pulse_sum == 0.0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / pulse_sum + 100.0)
+    };
+
+    let tnd_sum: f32 = triangle as f32 / 8227.0 + noise as f32 / 12241.0 + dmc as f32 / 22638.0;
+    let tnd_out: f32 = if tnd_sum == 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / tnd_sum + 100.0)
+    };
+
+    pulse_out + tnd_out
+}
+
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            period: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
+
+// Pulse 1 negates the sweep change in ones' complement, pulse 2 in twos'
+pub struct Pulse {
+    ones_complement: bool,
+    enabled: bool,
+    duty: u8,
+    sequence: usize,
+    envelope: Envelope,
+    length: u8,
+    timer_period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement: ones_complement,
+            enabled: false,
+            duty: 0,
+            sequence: 0,
+            envelope: Envelope::new(),
+            length: 0,
+            timer_period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.envelope.looping = data & 0x20 != 0;
+                self.envelope.constant = data & 0x10 != 0;
+                self.envelope.period = data & 0x0F;
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x700) | data as u16;
+            }
+            _ => {
+                self.timer_period = (self.timer_period & 0xFF) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.sequence = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    // Period the sweep would move to, can exceed 0x7FF
+    fn sweep_target(&self) -> u16 {
+        let change: u16 = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period + change
+        } else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        } else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+
+    // Muted regardless of the sweep enable flag
+    fn sweep_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence = (self.sequence + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn clock_half_frame(&mut self) {
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift != 0 && !self.sweep_muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.length == 0 || self.sweep_muted() || DUTY_TABLE[self.duty as usize][self.sequence] == 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
+
+// The control bit halts the length counter and keeps the linear counter
+// reloading. The sequencer only steps while both counters are non-zero, a
+// silenced triangle holds its last level rather than dropping to 0.
+pub struct Triangle {
+    enabled: bool,
+    control: bool,
+    linear_period: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    length: u8,
+    timer_period: u16,
+    timer: u16,
+    sequence: usize,
+}
+
+impl Triangle {
+    fn new() -> Self {
+        Triangle {
+            enabled: false,
+            control: false,
+            linear_period: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            length: 0,
+            timer_period: 0,
+            timer: 0,
+            sequence: 0,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.linear_period = data & 0x7F;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x700) | data as u16;
+            }
+            3 => {
+                self.timer_period = (self.timer_period & 0xFF) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.linear_counter > 0 && self.length > 0
+    }
+
+    // Clocked at the CPU rate, twice as fast as the pulse timers
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.active() {
+                self.sequence = (self.sequence + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_period;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_half_frame(&mut self) {
+        if !self.control && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    // Periods under 2 step too fast to hear and only add a pop, so like
+    // most emulators they're muted. That includes the power-on period of 0.
+    pub fn output(&self) -> u8 {
+        if self.timer_period < 2 {
+            0
+        } else {
+            TRIANGLE_SEQUENCE[self.sequence]
+        }
+    }
+}
+
+// Delta modulation channel. Sample bytes are fetched from $8000-$FFFF by
+// the bus, which stalls the CPU for it, and each bit moves the 7-bit output
+// level up or down by 2.
+pub struct Dmc {
+    irq_enabled: bool,
+    loop_flag: bool,
+    irq: bool,
+    timer_period: u16,
+    timer: u16,
+    level: u8,
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            loop_flag: false,
+            irq: false,
+            timer_period: DMC_RATE_TABLE[0],
+            timer: 0,
+            level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.loop_flag = data & 0x40 != 0;
+                self.timer_period = DMC_RATE_TABLE[(data & 0x0F) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_addr = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) | 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Clearing stops the sample once the buffered byte is played, setting
+    // starts it over only if it had finished
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    // Address to fetch from when the sample buffer has run dry
+    fn sample_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, byte: u8) {
+        self.sample_buffer = Some(byte);
+        // Addresses wrap from $FFFF back round to $8000
+        self.current_addr = if self.current_addr == 0xFFFF { 0x8000 } else { self.current_addr + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        // Level stays within 0-127, steps that would leave it are skipped
+        if !self.silence {
+            if self.shift & 1 == 1 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.shift = byte;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+pub struct Apu {
+    pulse: [Pulse; 2],
+    triangle: Triangle,
+    noise: u8,
+    dmc: Dmc,
+    frame_cycle: usize,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    resampler: Resampler,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Self {
+        Apu {
+            pulse: [Pulse::new(true), Pulse::new(false)],
+            triangle: Triangle::new(),
+            noise: 0,
+            dmc: Dmc::new(),
+            frame_cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            resampler: Resampler::new(sample_rate),
+            samples: Vec::new(),
+        }
+    }
+
+    // Reset button, silences every channel and restarts the frame counter
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.triangle = Triangle::new();
+        self.noise = 0;
+        self.dmc = Dmc::new();
+        self.frame_cycle = 0;
+        self.frame_irq = false;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Resampler::new(sample_rate);
+    }
+
+    // Current mixed output level before resampling
+    pub fn output(&self) -> f32 {
+        mix(self.pulse[0].output(), self.pulse[1].output(), self.triangle.output(), self.noise, self.dmc.output())
+    }
+
+    // $4015 without side effects: length counter status in the low bits,
+    // frame IRQ in bit 6 and DMC IRQ in bit 7
+    pub fn peek_status(&self) -> u8 {
+        let mut status: u8 = 0;
+        for (i, pulse) in self.pulse.iter().enumerate() {
+            if pulse.length > 0 {
+                status |= 1 << i;
+            }
+        }
+        if self.triangle.length > 0 {
+            status |= 0x04;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_irq {
+            status |= 0x40;
+        }
+        if self.dmc.irq {
+            status |= 0x80;
+        }
+        status
+    }
+
+    // CPU read of $4015, acknowledges the frame IRQ but not the DMC IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status: u8 = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+
+    // Address of the next DMC sample byte when the channel is waiting on one
+    pub fn dmc_sample_request(&self) -> Option<u16> {
+        self.dmc.sample_request()
+    }
+
+    // Hand the DMC the byte it asked for
+    pub fn dmc_fill(&mut self, byte: u8) {
+        self.dmc.fill(byte);
+    }
+
+    // CPU writes to $4000-$4017
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse[0].write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse[1].write(addr - 0x4004, data),
+            0x4015 => {
+                self.pulse[0].set_enabled(data & 0x01 != 0);
+                self.pulse[1].set_enabled(data & 0x02 != 0);
+                self.triangle.set_enabled(data & 0x04 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            0x4017 => {
+                self.five_step = data & 0x80 != 0;
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            _ => {}
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        for pulse in self.pulse.iter_mut() {
+            pulse.clock_quarter_frame();
+        }
+        self.triangle.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        for pulse in self.pulse.iter_mut() {
+            pulse.clock_half_frame();
+        }
+        self.triangle.clock_half_frame();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let step: Option<usize> = QUARTER_FRAME_STEPS.iter().position(|c| *c == self.frame_cycle);
+        match step {
+            Some(1) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            Some(3) if !self.five_step => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+            }
+            Some(3) => {}
+            Some(_) => self.clock_quarter_frame(),
+            None if self.frame_cycle == FIVE_STEP_LAST => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            None => {}
+        }
+    }
+
+    // Advance by a number of CPU cycles, one output decision per cycle
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            // Pulse timers run at the APU clock, half the CPU rate
+            if self.frame_cycle % 2 == 1 {
+                for pulse in self.pulse.iter_mut() {
+                    pulse.clock_timer();
+                }
+            }
+            self.triangle.clock_timer();
+            self.dmc.clock_timer();
+            self.clock_frame_counter();
+            let level: f32 = self.output();
+            self.resampler.push(level, &mut self.samples);
+        }
+    }
+
+    // Move every sample produced at the host rate into `out`
+    pub fn drain_samples(&mut self, out: &mut Vec<f32>) {
+        out.append(&mut self.samples);
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    // Run for roughly a tenth of a second, returning the cycle count
+    fn run_tenth_second(apu: &mut Apu) -> usize {
+        let ticks: usize = CPU_CLOCK_HZ as usize / 1000;
+        for _ in 0..ticks {
+            apu.tick(100);
+        }
+        ticks * 100
+    }
+
+    #[test_case(44_100)]
+    #[test_case(48_000)]
+    fn test_resampled_length(sample_rate: u32) {
+        let mut apu: Apu = Apu::new(sample_rate);
+        // Triangle step 7 is level 8, held while the channel is silent
+        apu.triangle.sequence = 7;
+        apu.triangle.timer_period = 0x10;
+        apu.noise = 8;
+        let level: f32 = apu.output();
+        let cycles: usize = run_tenth_second(&mut apu);
+
+        let mut out: Vec<f32> = Vec::new();
+        apu.drain_samples(&mut out);
+        let expected: usize = (cycles as f64 * sample_rate as f64 / CPU_CLOCK_HZ) as usize;
+        assert!(out.len().abs_diff(expected) <= 1, "{} samples, expected {}", out.len(), expected);
+
+        // Low-pass settles on the constant level
+        assert!((out[out.len() - 1] - level).abs() < 0.001);
+
+        apu.drain_samples(&mut out);
+        assert!(out.len().abs_diff(expected) <= 1);
+    }
+
+    #[test_case(0, 0, 0, 0, 0, 0.0)]
+    #[test_case(15, 0, 0, 0, 0, 0.1494)]
+    #[test_case(15, 15, 0, 0, 0, 0.2585)]
+    #[test_case(0, 0, 15, 0, 0, 0.2464)]
+    #[test_case(0, 0, 0, 15, 0, 0.1744)]
+    #[test_case(0, 0, 0, 0, 127, 0.5743)]
+    #[test_case(15, 15, 15, 15, 127, 1.0)]
+    fn test_mix_matches_hardware_levels(p1: u8, p2: u8, t: u8, n: u8, d: u8, expected: f32) {
+        let mixed: f32 = mix(p1, p2, t, n, d);
+        assert!((mixed - expected).abs() < 0.001, "mixed {}, expected {}", mixed, expected);
+    }
+
+    #[test]
+    fn test_mix_is_not_linear() {
+        // Two pulses at full volume are quieter than twice one
+        assert!(mix(15, 15, 0, 0, 0) < 2.0 * mix(15, 0, 0, 0, 0));
+    }
+
+    // Loudest output over a full duty cycle
+    fn peak_output(pulse: &mut Pulse) -> u8 {
+        let mut peak: u8 = 0;
+        for _ in 0..(pulse.timer_period as usize + 1) * 8 {
+            pulse.clock_timer();
+            peak = peak.max(pulse.output());
+        }
+        peak
+    }
+
+    fn constant_pulse(ones_complement: bool, period: u16) -> Pulse {
+        let mut pulse: Pulse = Pulse::new(ones_complement);
+        pulse.set_enabled(true);
+        pulse.write(0, 0b1011_1111);
+        pulse.write(2, period as u8);
+        pulse.write(3, (period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn test_sweep_down_mutes_below_period_8() {
+        let mut pulse: Pulse = constant_pulse(true, 0x20);
+        // Enabled, divider period 0, negate, shift 1
+        pulse.write(1, 0b1000_1001);
+        assert_eq!(peak_output(&mut pulse), 15);
+
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period, 15);
+        assert_eq!(peak_output(&mut pulse), 15);
+
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period, 7);
+        assert_eq!(peak_output(&mut pulse), 0);
+
+        // Muted channels stop sweeping
+        pulse.clock_half_frame();
+        assert_eq!(pulse.timer_period, 7);
+    }
+
+    #[test_case(true, 0x0F)]
+    #[test_case(false, 0x10)]
+    fn test_sweep_negate_complement(ones_complement: bool, expected: u16) {
+        let mut pulse: Pulse = constant_pulse(ones_complement, 0x20);
+        pulse.write(1, 0b1000_1001);
+        assert_eq!(pulse.sweep_target(), expected);
+    }
+
+    #[test]
+    fn test_sweep_target_overflow_mutes() {
+        // Target of 0x600 + 0x300 is past 0x7FF, even with the sweep disabled
+        let mut pulse: Pulse = constant_pulse(false, 0x600);
+        pulse.write(1, 0b0000_0001);
+        assert_eq!(peak_output(&mut pulse), 0);
+
+        pulse.write(1, 0b0000_0010);
+        assert_eq!(peak_output(&mut pulse), 15);
+    }
+
+    #[test]
+    fn test_sweep_divider_period() {
+        let mut pulse: Pulse = constant_pulse(false, 0x100);
+        // Divider period 2, upward, shift 4
+        pulse.write(1, 0b1010_0100);
+        let mut periods: Vec<u16> = Vec::new();
+        for _ in 0..6 {
+            pulse.clock_half_frame();
+            periods.push(pulse.timer_period);
+        }
+        assert_eq!(periods, vec![0x110, 0x110, 0x110, 0x121, 0x121, 0x121]);
+    }
+
+    #[test]
+    fn test_reset_silences_pulses() {
+        let mut apu: Apu = Apu::default();
+        apu.write_register(0x4015, 0x03);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0x80);
+        apu.write_register(0x4003, 0x08);
+        assert!(apu.pulse[0].length > 0);
+
+        apu.reset();
+        assert_eq!(apu.pulse[0].length, 0);
+        assert_eq!(apu.output(), 0.0);
+    }
+
+    #[test]
+    fn test_status_read_clears_frame_irq() {
+        let mut apu: Apu = Apu::default();
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4003, 0x08);
+        apu.dmc.irq = true;
+        for _ in 0..QUARTER_FRAME_STEPS[3] {
+            apu.tick(1);
+        }
+
+        assert_eq!(apu.read_status(), 0xC1);
+        assert_eq!(apu.read_status(), 0x81);
+        assert_eq!(apu.peek_status(), 0x81);
+    }
+
+    // Play a one byte sample at the fastest rate, returning the output
+    // level after each change
+    fn dmc_levels(byte: u8) -> Vec<u8> {
+        let mut apu: Apu = Apu::default();
+        apu.write_register(0x4010, 0x0F);
+        apu.write_register(0x4011, 64);
+        apu.write_register(0x4012, 0x00);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.dmc_sample_request(), Some(0xC000));
+        apu.dmc_fill(byte);
+        assert_eq!(apu.dmc_sample_request(), None);
+
+        let mut levels: Vec<u8> = Vec::new();
+        for _ in 0..54 * 24 {
+            apu.tick(1);
+            if levels.last().copied().unwrap_or(64) != apu.dmc.output() {
+                levels.push(apu.dmc.output());
+            }
+        }
+        levels
+    }
+
+    #[test_case(0b0000_0101, vec![66, 64, 66, 64, 62, 60, 58, 56]; "mixed bits")]
+    #[test_case(0xFF, vec![66, 68, 70, 72, 74, 76, 78, 80]; "all ones")]
+    fn test_dmc_level_follows_sample_bits(byte: u8, expected: Vec<u8>) {
+        assert_eq!(dmc_levels(byte), expected);
+    }
+
+    #[test]
+    fn test_dmc_level_clamped() {
+        let mut dmc: Dmc = Dmc::new();
+        dmc.write(0, 0x0F);
+        dmc.write(1, 126);
+        dmc.silence = false;
+        dmc.shift = 0xFF;
+        dmc.clock_timer();
+        assert_eq!(dmc.output(), 126);
+    }
+
+    #[test]
+    fn test_dmc_loop_and_irq() {
+        let mut apu: Apu = Apu::default();
+        // Two byte sample at $C040, IRQ on
+        apu.write_register(0x4010, 0x8F);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x00);
+        apu.dmc.sample_length = 2;
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.peek_status() & 0x10, 0x10);
+
+        apu.dmc_fill(0);
+        apu.dmc.sample_buffer = None;
+        assert_eq!(apu.dmc_sample_request(), Some(0xC041));
+        apu.dmc_fill(0);
+        assert_eq!(apu.peek_status() & 0x90, 0x80);
+        assert!(apu.dmc_irq());
+
+        // Looping starts over at the sample address instead
+        apu.write_register(0x4010, 0x4F);
+        assert!(!apu.dmc_irq());
+        apu.write_register(0x4015, 0x10);
+        apu.dmc_fill(0);
+        apu.dmc.sample_buffer = None;
+        apu.dmc_fill(0);
+        assert!(!apu.dmc_irq());
+        apu.dmc.sample_buffer = None;
+        assert_eq!(apu.dmc_sample_request(), Some(0xC040));
+    }
+
+    #[test]
+    fn test_irq_inhibit() {
+        let mut apu: Apu = Apu::default();
+        apu.write_register(0x4017, 0x40);
+        for _ in 0..QUARTER_FRAME_STEPS[3] {
+            apu.tick(1);
+        }
+        assert!(!apu.frame_irq());
+    }
+
+    fn programmed_triangle(linear: u8) -> Apu {
+        let mut apu: Apu = Apu::default();
+        apu.write_register(0x4015, 0x04);
+        apu.write_register(0x4008, linear);
+        apu.write_register(0x400A, 0x02);
+        // Length 254
+        apu.write_register(0x400B, 0x08);
+        apu
+    }
+
+    // One full timer period, a single sequencer step when active
+    fn clock_triangle_period(apu: &mut Apu) {
+        for _ in 0..3 {
+            apu.triangle.clock_timer();
+        }
+    }
+
+    #[test]
+    fn test_triangle_silenced_by_linear_counter() {
+        let mut apu: Apu = programmed_triangle(0x03);
+        clock_triangle_period(&mut apu);
+        assert_eq!(apu.triangle.sequence, 0);
+
+        // First quarter frame reloads, then it counts down 3, 2, 1, 0
+        for expected in [3, 2, 1] {
+            apu.clock_quarter_frame();
+            assert_eq!(apu.triangle.linear_counter, expected);
+            let sequence: usize = apu.triangle.sequence;
+            clock_triangle_period(&mut apu);
+            assert_eq!(apu.triangle.sequence, (sequence + 1) % 32);
+        }
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 0);
+
+        let sequence: usize = apu.triangle.sequence;
+        let level: u8 = apu.triangle.output();
+        apu.tick(50);
+        assert_eq!(apu.triangle.sequence, sequence);
+        assert_eq!(apu.triangle.output(), level);
+        assert_eq!(level, 12);
+        // Length is still running, only the linear counter ran out
+        assert_eq!(apu.peek_status() & 0x04, 0x04);
+    }
+
+    #[test]
+    fn test_triangle_control_keeps_reloading() {
+        let mut apu: Apu = programmed_triangle(0x82);
+        for _ in 0..5 {
+            apu.clock_quarter_frame();
+            apu.clock_half_frame();
+            assert_eq!(apu.triangle.linear_counter, 2);
+        }
+        assert_eq!(apu.triangle.length, 254);
+
+        // Clearing control lets the next quarter frame drop the reload flag
+        apu.write_register(0x4008, 0x02);
+        apu.clock_quarter_frame();
+        apu.clock_quarter_frame();
+        apu.clock_quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 0);
+        assert!(!apu.triangle.active());
+    }
+
+    #[test]
+    fn test_triangle_disable_clears_length() {
+        let mut apu: Apu = programmed_triangle(0x03);
+        assert_eq!(apu.peek_status() & 0x04, 0x04);
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(apu.peek_status() & 0x04, 0x00);
+    }
+
+    #[test]
+    fn test_unused_registers_ignored() {
+        let mut apu: Apu = Apu::default();
+        apu.write_register(0x4015, 0x03);
+        apu.write_register(0x4000, 0xBF);
+        apu.write_register(0x4002, 0x40);
+        apu.write_register(0x4003, 0x08);
+        apu.tick(16);
+        let level: f32 = apu.output();
+        let period: u16 = apu.pulse[0].timer_period;
+
+        for data in [0x00, 0xFF] {
+            apu.write_register(0x4009, data);
+            apu.write_register(0x400D, data);
+        }
+        assert_eq!(apu.output(), level);
+        assert_eq!(apu.peek_status(), 0x01);
+        assert_eq!(apu.pulse[0].timer_period, period);
+        assert_eq!(apu.frame_cycle, 16);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::opcodes::{AddressingMode, OpCode, OPCODES};
+use std::collections::HashMap;
+
+// Small two-pass assembler for the official opcodes, so tests can be written
+// as text. One instruction per line, `label:` definitions, `;` comments and
+// `$` hex or decimal numbers. Numbers with at most two hex digits use zero
+// page addressing when the instruction has it.
+
+const BRANCHES: [&str; 8] = ["BPL", "BMI", "BVC", "BVS", "BCC", "BCS", "BNE", "BEQ"];
+
+enum Value {
+    Number(u16, bool),
+    Label(String),
+}
+
+enum Operand {
+    Implied,
+    Immediate(Value),
+    Address(Value, Option<char>),
+    Indirect(Value),
+    IndirectX(Value),
+    IndirectY(Value),
+}
+
+struct Instruction {
+    opcode: &'static OpCode,
+    operand: Operand,
+    line: usize,
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    let text: &str = text.trim();
+    if let Some(hex) = text.strip_prefix('$') {
+        let number: u16 = u16::from_str_radix(hex, 16).map_err(|_| format!("bad number {}", text))?;
+        return Ok(Value::Number(number, hex.len() > 2));
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        let number: u16 = text.parse().map_err(|_| format!("bad number {}", text))?;
+        return Ok(Value::Number(number, number > 0xFF));
+    }
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Ok(Value::Label(text.to_string()));
+    }
+    Err(format!("bad operand {}", text))
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let text: String = text.split_whitespace().collect::<String>().to_uppercase();
+    if text.is_empty() || text == "A" {
+        return Ok(Operand::Implied);
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_value(value)?));
+    }
+    if let Some(inner) = text.strip_prefix('(') {
+        if let Some(value) = inner.strip_suffix(",X)") {
+            return Ok(Operand::IndirectX(parse_value(value)?));
+        }
+        if let Some(value) = inner.strip_suffix("),Y") {
+            return Ok(Operand::IndirectY(parse_value(value)?));
+        }
+        if let Some(value) = inner.strip_suffix(')') {
+            return Ok(Operand::Indirect(parse_value(value)?));
+        }
+        return Err(format!("bad operand {}", text));
+    }
+    match text.split_once(',') {
+        Some((value, "X")) => Ok(Operand::Address(parse_value(value)?, Some('X'))),
+        Some((value, "Y")) => Ok(Operand::Address(parse_value(value)?, Some('Y'))),
+        Some(_) => Err(format!("bad operand {}", text)),
+        None => Ok(Operand::Address(parse_value(&text)?, None)),
+    }
+}
+
+fn find_opcode(mnemonic: &str, mode: AddressingMode, len: u8) -> Option<&'static OpCode> {
+    OPCODES
+        .iter()
+        .find(|op| op.operation == mnemonic && op.mode == mode && op.len == len)
+}
+
+// Opcode for a mnemonic and operand, preferring zero page for short numbers
+fn select_opcode(mnemonic: &str, operand: &Operand) -> Option<&'static OpCode> {
+    let code: Option<u8> = match (mnemonic, operand) {
+        ("JMP", Operand::Address(_, None)) => Some(0x4C),
+        ("JMP", Operand::Indirect(_)) => Some(0x6C),
+        ("JSR", Operand::Address(_, None)) => Some(0x20),
+        _ => None,
+    };
+    if let Some(code) = code {
+        return OPCODES.iter().find(|op| op.code == code);
+    }
+    if BRANCHES.contains(&mnemonic) {
+        return match operand {
+            Operand::Address(_, None) => find_opcode(mnemonic, AddressingMode::NoneAddressing, 2),
+            _ => None,
+        };
+    }
+
+    match operand {
+        Operand::Implied => find_opcode(mnemonic, AddressingMode::NoneAddressing, 1),
+        Operand::Immediate(_) => find_opcode(mnemonic, AddressingMode::Immediate, 2),
+        Operand::IndirectX(_) => find_opcode(mnemonic, AddressingMode::Indirect_X, 2),
+        Operand::IndirectY(_) => find_opcode(mnemonic, AddressingMode::Indirect_Y, 2),
+        Operand::Indirect(_) => None,
+        Operand::Address(value, index) => {
+            let short: bool = matches!(value, Value::Number(_, false));
+            let (zero_page, absolute): (AddressingMode, AddressingMode) = match index {
+                Some('X') => (AddressingMode::ZeroPage_X, AddressingMode::Absolute_X),
+                Some(_) => (AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y),
+                None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+            };
+            let zero_page_op: Option<&'static OpCode> = if short {
+                find_opcode(mnemonic, zero_page, 2)
+            } else {
+                None
+            };
+            zero_page_op.or_else(|| find_opcode(mnemonic, absolute, 3))
+        }
+    }
+}
+
+fn resolve(value: &Value, labels: &HashMap<String, u16>) -> Result<u16, String> {
+    match value {
+        Value::Number(number, _) => Ok(*number),
+        Value::Label(label) => labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("unknown label {}", label)),
+    }
+}
+
+// Assemble source into bytes to be loaded at `origin`
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, String> {
+    // First pass: pick opcodes, which fixes every instruction's address
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut instructions: Vec<(u16, Instruction)> = Vec::new();
+    let mut addr: u16 = origin;
+    for (index, raw) in source.lines().enumerate() {
+        let line: usize = index + 1;
+        let mut text: &str = raw.split(';').next().unwrap_or("").trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            labels.insert(label.trim().to_uppercase(), addr);
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operand_text): (&str, &str) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mnemonic: String = mnemonic.to_uppercase();
+        let operand: Operand = parse_operand(operand_text).map_err(|e| format!("line {}: {}", line, e))?;
+        let opcode: &'static OpCode = select_opcode(&mnemonic, &operand)
+            .ok_or_else(|| format!("line {}: no addressing mode for {}", line, text))?;
+        instructions.push((addr, Instruction { opcode: opcode, operand: operand, line: line }));
+        addr = addr.wrapping_add(opcode.len as u16);
+    }
+
+    // Second pass: encode operands now every label is known
+    let mut bytes: Vec<u8> = Vec::new();
+    for (addr, instruction) in instructions.iter() {
+        bytes.push(instruction.opcode.code);
+        let value: u16 = match &instruction.operand {
+            Operand::Implied => continue,
+            Operand::Immediate(value)
+            | Operand::Address(value, _)
+            | Operand::Indirect(value)
+            | Operand::IndirectX(value)
+            | Operand::IndirectY(value) => resolve(value, &labels)
+                .map_err(|e| format!("line {}: {}", instruction.line, e))?,
+        };
+
+        if BRANCHES.contains(&instruction.opcode.operation) {
+            let offset: i32 = value as i32 - (*addr as i32 + 2);
+            if !(-128..=127).contains(&offset) {
+                return Err(format!("line {}: branch out of range", instruction.line));
+            }
+            bytes.push(offset as u8);
+        } else if instruction.opcode.len == 2 {
+            if value > 0xFF {
+                return Err(format!("line {}: operand {:#x} does not fit in a byte", instruction.line, value));
+            }
+            bytes.push(value as u8);
+        } else {
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("BRK", vec![0x00]; "implied")]
+    #[test_case("ASL A", vec![0x0A]; "accumulator")]
+    #[test_case("LDA #$05", vec![0xA9, 0x05]; "immediate")]
+    #[test_case("LDA #10", vec![0xA9, 0x0A]; "decimal immediate")]
+    #[test_case("STA $10", vec![0x85, 0x10]; "zero page")]
+    #[test_case("STA $0010", vec![0x8D, 0x10, 0x00]; "forced absolute")]
+    #[test_case("LDX $10,Y", vec![0xB6, 0x10]; "zero page y")]
+    #[test_case("LDA $1234,X", vec![0xBD, 0x34, 0x12]; "absolute x")]
+    #[test_case("STA ($20),Y", vec![0x91, 0x20]; "indirect y")]
+    #[test_case("LDA ($20,X)", vec![0xA1, 0x20]; "indirect x")]
+    #[test_case("JMP ($1234)", vec![0x6C, 0x34, 0x12]; "indirect jump")]
+    #[test_case("jsr $8000", vec![0x20, 0x00, 0x80]; "lower case")]
+    fn test_assemble_modes(source: &str, expected: Vec<u8>) {
+        assert_eq!(assemble(source, 0x8000), Ok(expected));
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let source: &str = "
+            LDX #$02
+        loop:
+            DEX         ; count down
+            BNE loop
+            JMP done
+        done: BRK";
+        let expected: Vec<u8> = vec![0xA2, 0x02, 0xCA, 0xD0, 0xFD, 0x4C, 0x08, 0x06, 0x00];
+        assert_eq!(assemble(source, 0x0600), Ok(expected));
+    }
+
+    #[test_case("FOO", "line 1: no addressing mode for FOO")]
+    #[test_case("NOP\nJMP nowhere", "line 2: unknown label NOWHERE")]
+    #[test_case("LDA #$123", "line 1: operand 0x123 does not fit in a byte")]
+    #[test_case("STX $1234,X", "line 1: no addressing mode for STX $1234,X")]
+    fn test_assemble_errors(source: &str, expected: &str) {
+        assert_eq!(assemble(source, 0x8000), Err(expected.to_string()));
+    }
+}
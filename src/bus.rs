@@ -1,51 +1,217 @@
+use crate::apu::Apu;
+use crate::frame::Frame;
+use crate::joypad::Joypad;
+use crate::cartridge::{Cartridge, SharedCartridge};
 use crate::mem::Mem;
+#[cfg(test)]
+use crate::mem::FlatMemory;
+use crate::ppu::PPU;
+#[cfg(feature = "profile")]
+use crate::profile::{self, ProfileStats};
+use crate::render;
 use crate::rom::Rom;
+#[cfg(test)]
+use crate::rom::Mirroring;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 const CPU_START: u16 = 0x0000;
 const CPU_END: u16 = 0x1FFF;
+// 2KB of RAM repeated four times over 0x0000-0x1FFF
+const RAM_MIRROR_MASK: u16 = 0x07FF;
 const PPU_START: u16 = 0x2000;
 const PPU_END: u16 = 0x3FFF;
+const APU_START: u16 = 0x4000;
+const APU_END: u16 = 0x4017;
+const OAM_DMA: u16 = 0x4014;
+// NTSC master clock divided down for the CPU/APU and the PPU
+pub const MASTER_CYCLES_PER_CPU_CYCLE: usize = 12;
+pub const MASTER_CYCLES_PER_DOT: usize = 4;
+const DMC_STALL: u8 = 4;
+const DMC_STALL_DURING_OAM_DMA: u8 = 2;
+const JOYPAD_1: u16 = 0x4016;
+const JOYPAD_2: u16 = 0x4017;
+const SRAM_START: u16 = 0x6000;
+const SRAM_END: u16 = 0x7FFF;
 const ROM_START: u16 = 0x8000;
 const ROM_END: u16 = 0xFFFF;
 
+bitflags! {
+    pub struct IrqSource: u8 {
+        const MAPPER        = 0b0000_0001;
+        const FRAME_COUNTER = 0b0000_0010;
+        const DMC           = 0b0000_0100;
+    }
+}
+
+// Device that owns an address in the CPU memory map
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MemRegion {
+    Ram,
+    PpuRegister,
+    ApuIo,
+    PrgRam,
+    PrgRom,
+    Unmapped,
+}
+
+// Decode an address without reading it
+pub fn region(addr: u16) -> MemRegion {
+    match addr {
+        CPU_START ..= CPU_END => MemRegion::Ram,
+        PPU_START ..= PPU_END => MemRegion::PpuRegister,
+        APU_START ..= APU_END => MemRegion::ApuIo,
+        SRAM_START ..= SRAM_END => MemRegion::PrgRam,
+        ROM_START ..= ROM_END => MemRegion::PrgRom,
+        _ => MemRegion::Unmapped,
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub is_write: bool,
+}
+
+// Address and the hook called with each byte read from or written to it
+type Watch = (u16, Box<dyn FnMut(u8)>);
+
 pub struct Bus {
     ram: [u8; 2048],
-    rom: Rom,
+    cartridge: SharedCartridge,
+    ppu: PPU,
+    apu: Apu,
+    joypads: [Joypad; 2],
+    irq_lines: IrqSource,
+    cycles: usize,
+    master_cycles: usize,
+    // While set, ticks from the CPU are owed instead of run and get paid
+    // back one master cycle at a time by clock
+    pub defer_ticks: bool,
+    owed_master_cycles: usize,
+    frame_ready: bool,
+    // Page written to $4014, copied to OAM once the writing instruction ends
+    oam_dma_page: Option<u8>,
+    read_watch: Option<Watch>,
+    write_watch: Option<Watch>,
+    pub bus_trace: Option<Box<dyn FnMut(BusAccess)>>,
+    // Called at the end of every frame for the (port 1, port 2) button
+    // bytes the next frame sees, so scripted input doesn't depend on when
+    // the game polls
+    pub on_frame_input: Option<Box<dyn FnMut() -> (u8, u8)>>,
+    // Test-only: the whole address space is plain RAM
+    #[cfg(test)]
+    flat: Option<FlatMemory>,
+    #[cfg(feature = "profile")]
+    profile: ProfileStats,
 }
 
 
+// Controller reads only drive the low bits, the top three keep the last
+// value on the data bus, which is the high byte of the address
+fn joypad_open_bus(addr: u16) -> u8 {
+    (addr >> 8) as u8 & 0xE0
+}
+
 impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let data: u8 = match addr {
+            #[cfg(test)]
+            _ if self.flat.is_some() => self.peek(addr),
+            PPU_START ..= PPU_END => {
+                let mirrored_addr: u16 = addr & 0x2007;
+                self.ppu.read_register(mirrored_addr)
+            }
+            0x4015 => self.apu.read_status(),
+            JOYPAD_1 | JOYPAD_2 => {
+                let index: usize = (addr - JOYPAD_1) as usize;
+                joypad_open_bus(addr) | self.joypads[index].read()
+            }
+            // Write-only APU registers and the gap below PRG RAM
+            _ if (APU_START..SRAM_START).contains(&addr) => {
+                println!("Ignoring mem access at {}.", addr);
+                0
+            }
+            _ => self.peek(addr),
+        };
+        if let Some(trace) = self.bus_trace.as_mut() {
+            trace(BusAccess { addr: addr, value: data, is_write: false });
+        }
+        if let Some((watch_addr, hook)) = self.read_watch.as_mut() {
+            if *watch_addr == addr {
+                hook(data);
+            }
+        }
+        data
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        #[cfg(test)]
+        if let Some(flat) = self.flat.as_ref() {
+            return flat.peek(addr);
+        }
+
         match addr {
             CPU_START ..= CPU_END => {
-                let mirrored_addr: u16 = addr & 0b0111_1111_1111;
+                let mirrored_addr: u16 = addr & RAM_MIRROR_MASK;
                 self.ram[mirrored_addr as usize]
             }
             PPU_START ..= PPU_END => {
                 let mirrored_addr: u16 = addr & 0x2007;
-                0
+                self.ppu.peek_register(mirrored_addr)
             }
-            ROM_START ..= ROM_END => {
-                self.read_prg_rom(addr)
+            0x4015 => self.apu.peek_status(),
+            JOYPAD_1 | JOYPAD_2 => {
+                let index: usize = (addr - JOYPAD_1) as usize;
+                joypad_open_bus(addr) | self.joypads[index].peek()
             }
-            _ => {
-                println!("Ignoring mem access at {}.", addr);
-                0
+            SRAM_START ..= ROM_END => {
+                self.cartridge.borrow().read_cpu(addr)
             }
+            // Nothing drives the data bus, mem_read reports these
+            _ => 0,
         }
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if let Some(trace) = self.bus_trace.as_mut() {
+            trace(BusAccess { addr: addr, value: data, is_write: true });
+        }
+        if let Some((watch_addr, hook)) = self.write_watch.as_mut() {
+            if *watch_addr == addr {
+                hook(data);
+            }
+        }
+        #[cfg(test)]
+        if let Some(flat) = self.flat.as_mut() {
+            flat.mem_write(addr, data);
+            return;
+        }
+
         match addr {
             CPU_START ..= CPU_END => {
-                let mirrored_addr: u16 = addr & 0b0111_1111_1111;
+                let mirrored_addr: u16 = addr & RAM_MIRROR_MASK;
                 self.ram[mirrored_addr as usize] = data;
             }
             PPU_START ..= PPU_END => {
                 let mirrored_addr: u16 = addr & 0x2007;
+                self.ppu.write_register(mirrored_addr, data);
             }
-            ROM_START ..= ROM_END => {
-                panic!("Attempting to write to cartridge space.");
+            OAM_DMA => {
+                self.oam_dma_page = Some(data);
+            }
+            JOYPAD_1 => {
+                // The strobe line is shared by both ports
+                for joypad in self.joypads.iter_mut() {
+                    joypad.write(data);
+                }
+            }
+            APU_START ..= APU_END => {
+                self.apu.write_register(addr, data);
+            }
+            SRAM_START ..= ROM_END => {
+                self.cartridge.borrow_mut().write_cpu(addr, data);
             }
             _ => {
                 println!("Ignoring mem write at {}.", addr);
@@ -56,17 +222,744 @@ impl Mem for Bus {
 
 impl Bus {
     pub fn new(rom: Rom) -> Self{
+        let cartridge: SharedCartridge = Rc::new(RefCell::new(Cartridge::new(&rom)));
+        let mut ppu: PPU = PPU::new(cartridge.clone(), rom.mirroring);
+        ppu.start_warmup();
         Bus {
             ram: [0; 2048],
-            rom: rom,
+            cartridge: cartridge,
+            ppu: ppu,
+            apu: Apu::default(),
+            joypads: [Joypad::new(), Joypad::new()],
+            irq_lines: IrqSource::empty(),
+            cycles: 0,
+            master_cycles: 0,
+            defer_ticks: false,
+            owed_master_cycles: 0,
+            frame_ready: false,
+            oam_dma_page: None,
+            read_watch: None,
+            write_watch: None,
+            bus_trace: None,
+            on_frame_input: None,
+            #[cfg(test)]
+            flat: None,
+            #[cfg(feature = "profile")]
+            profile: ProfileStats::default(),
+        }
+    }
+
+    // Bus where the whole address space is RAM, devices still tick but
+    // can't be reached by the CPU. Only exists in test builds.
+    #[cfg(test)]
+    pub fn flat() -> Self {
+        let rom: Rom = Rom {
+            prg: vec![0; 0x4000],
+            chr: vec![],
+            mapper: 0,
+            mirroring: Mirroring::HORIZONTAL,
+            has_battery: false,
+        };
+        let mut bus: Bus = Bus::new(rom);
+        bus.flat = Some(FlatMemory::new());
+        bus
+    }
+
+    // Call `hook` with the value each time the CPU reads `addr`
+    pub fn set_read_watch<F>(&mut self, addr: u16, hook: F)
+    where
+        F: FnMut(u8) + 'static,
+    {
+        self.read_watch = Some((addr, Box::new(hook)));
+    }
+
+    // Call `hook` with the value each time the CPU writes `addr`
+    pub fn set_write_watch<F>(&mut self, addr: u16, hook: F)
+    where
+        F: FnMut(u8) + 'static,
+    {
+        self.write_watch = Some((addr, Box::new(hook)));
+    }
+
+    pub fn ram_snapshot(&self) -> [u8; 2048] {
+        self.ram
+    }
+
+    pub fn restore_ram(&mut self, ram: &[u8; 2048]) {
+        self.ram = *ram;
+    }
+
+    // Start addresses of every occurrence of `needle` in work RAM and then
+    // PRG RAM, for finding cheats. Matches don't span the two.
+    pub fn search(&self, needle: &[u8]) -> Vec<u16> {
+        let mut found: Vec<u16> = Vec::new();
+        if needle.is_empty() {
+            return found;
+        }
+        for (start, len) in [(CPU_START, 0x0800), (SRAM_START, 0x2000)] {
+            let haystack: Vec<u8> = self.read_range(start, len);
+            for (offset, window) in haystack.windows(needle.len()).enumerate() {
+                if window == needle {
+                    found.push(start + offset as u16);
+                }
+            }
+        }
+        found
+    }
+
+    // Cartridge PRG RAM at $6000-$7FFF, what battery saves hold
+    pub fn sram(&self) -> Vec<u8> {
+        self.cartridge.borrow().prg_ram().to_vec()
+    }
+
+    // Restore PRG RAM from a save, short saves leave the rest untouched
+    pub fn load_sram(&mut self, data: &[u8]) {
+        self.cartridge.borrow_mut().load_prg_ram(data);
+    }
+
+    // Power-on contents of work RAM
+    pub fn fill_ram(&mut self, value: u8) {
+        self.ram = [value; 2048];
+    }
+
+    // Bytes from `start` on as the CPU would see them, without side effects,
+    // for hex dumps. Addresses past $FFFF wrap round to $0000.
+    pub fn read_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.peek(start.wrapping_add(i as u16))).collect()
+    }
+
+    // Reset the devices wired to the reset line, RAM and cartridge keep their state
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.frame_ready = false;
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    // Controller in port 1 or 2, numbered from 1 like on the console.
+    // Panics for any other port.
+    pub fn joypad_mut(&mut self, port: usize) -> &mut Joypad {
+        assert!(port == 1 || port == 2, "No controller port {}, ports are 1 and 2.", port);
+        &mut self.joypads[port - 1]
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // Restore the cycle count from a snapshot, keeping the master clock on
+    // the matching CPU cycle boundary
+    pub fn set_cycles(&mut self, cycles: usize) {
+        self.cycles = cycles;
+        self.master_cycles = cycles * MASTER_CYCLES_PER_CPU_CYCLE;
+        self.owed_master_cycles = 0;
+    }
+
+    pub fn master_cycles(&self) -> usize {
+        self.master_cycles
+    }
+
+    pub fn owed_master_cycles(&self) -> usize {
+        self.owed_master_cycles
+    }
+
+    // Run every master cycle still owed from deferred ticks
+    pub fn catch_up(&mut self) {
+        while self.owed_master_cycles > 0 {
+            self.clock();
+        }
+    }
+
+    // Advance the rest of the system by a number of CPU cycles
+    pub fn tick(&mut self, cycles: u8) {
+        let master_cycles: usize = cycles as usize * MASTER_CYCLES_PER_CPU_CYCLE;
+        if self.defer_ticks {
+            self.owed_master_cycles += master_cycles;
+            return;
+        }
+        for _ in 0..cycles {
+            // Console::clock can stop part way through a CPU cycle, finish
+            // those a master cycle at a time
+            if !self.master_cycles.is_multiple_of(MASTER_CYCLES_PER_CPU_CYCLE) {
+                for _ in 0..MASTER_CYCLES_PER_CPU_CYCLE {
+                    self.clock();
+                }
+                continue;
+            }
+            self.master_cycles += MASTER_CYCLES_PER_CPU_CYCLE;
+            self.owed_master_cycles = self.owed_master_cycles.saturating_sub(MASTER_CYCLES_PER_CPU_CYCLE);
+            self.step_ppu((MASTER_CYCLES_PER_CPU_CYCLE / MASTER_CYCLES_PER_DOT) as u8);
+            self.step_apu();
+        }
+    }
+
+    // Advance one master clock cycle. The PPU takes a dot every 4th and the
+    // APU a cycle every 12th, landing on the same cycle as the PPU's third
+    // dot.
+    pub fn clock(&mut self) {
+        self.master_cycles += 1;
+        self.owed_master_cycles = self.owed_master_cycles.saturating_sub(1);
+        if self.master_cycles.is_multiple_of(MASTER_CYCLES_PER_DOT) {
+            self.step_ppu(1);
+        }
+        if self.master_cycles.is_multiple_of(MASTER_CYCLES_PER_CPU_CYCLE) {
+            self.step_apu();
+        }
+    }
+
+    fn step_ppu(&mut self, dots: u8) {
+        if self.tick_ppu(dots) {
+            self.frame_ready = true;
+            if let Some(input) = self.on_frame_input.as_mut() {
+                let (port1, port2): (u8, u8) = input();
+                self.joypads[0].set_state(port1);
+                self.joypads[1].set_state(port2);
+            }
+        }
+    }
+
+    // One CPU cycle for the APU
+    fn step_apu(&mut self) {
+        self.cycles += 1;
+        self.tick_apu(1);
+    }
+
+    #[cfg(not(feature = "profile"))]
+    fn tick_apu(&mut self, cycles: u8) {
+        self.apu.tick(cycles);
+    }
+
+    #[cfg(not(feature = "profile"))]
+    fn tick_ppu(&mut self, dots: u8) -> bool {
+        self.ppu.tick(dots)
+    }
+
+    #[cfg(feature = "profile")]
+    fn tick_apu(&mut self, cycles: u8) {
+        let apu: &mut Apu = &mut self.apu;
+        profile::timed(&mut self.profile.apu, || apu.tick(cycles));
+    }
+
+    #[cfg(feature = "profile")]
+    fn tick_ppu(&mut self, dots: u8) -> bool {
+        let ppu: &mut PPU = &mut self.ppu;
+        let frame_done: bool = profile::timed(&mut self.profile.ppu, || ppu.tick(dots));
+        if frame_done {
+            self.profile.frames += 1;
+        }
+        frame_done
+    }
+
+    // PPU and APU time and completed frames, CPU time is left to the caller
+    #[cfg(feature = "profile")]
+    pub fn profile(&self) -> ProfileStats {
+        self.profile
+    }
+
+    #[cfg(feature = "profile")]
+    pub fn reset_profile(&mut self) {
+        self.profile = ProfileStats::default();
+    }
+
+    // Run any DMA requested since the last instruction, returning how many
+    // cycles the CPU was stalled for. OAM DMA takes a halt cycle, one more
+    // to line up if that lands on an odd cycle, then a read and a write for
+    // each of the 256 bytes. A DMC fetch usually costs 4 cycles, but only 2
+    // when it lands inside an OAM DMA that already has the CPU halted.
+    pub fn run_dma(&mut self) -> usize {
+        let mut stall: usize = 0;
+        if let Some(page) = self.oam_dma_page.take() {
+            let owed_cycles: usize = self.owed_master_cycles / MASTER_CYCLES_PER_CPU_CYCLE;
+            stall = 513 + (self.cycles + owed_cycles) % 2;
+            for _ in 0..stall - 512 {
+                self.tick(1);
+            }
+            for offset in 0..=0xFF {
+                let data: u8 = self.mem_read((page as u16) << 8 | offset);
+                self.tick(1);
+                self.ppu.write_to_oam_data(data);
+                self.tick(1);
+                stall += self.fetch_dmc_sample(DMC_STALL_DURING_OAM_DMA);
+            }
+        }
+        stall + self.fetch_dmc_sample(DMC_STALL)
+    }
+
+    fn fetch_dmc_sample(&mut self, stall: u8) -> usize {
+        let addr: u16 = match self.apu.dmc_sample_request() {
+            Some(addr) => addr,
+            None => return 0,
+        };
+        let byte: u8 = self.mem_read(addr);
+        self.apu.dmc_fill(byte);
+        for _ in 0..stall {
+            self.tick(1);
+        }
+        stall as usize
+    }
+
+    // Returns true once per completed PPU frame
+    pub fn poll_frame(&mut self) -> bool {
+        let ready: bool = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    // Render the PPU state into a new frame once per completed frame
+    pub fn take_frame(&mut self) -> Option<Frame> {
+        if !self.poll_frame() {
+            return None;
+        }
+        let mut frame: Frame = Frame::new();
+        render::render(&self.ppu, &mut frame);
+        Some(frame)
+    }
+
+    pub fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+
+    // Assert or clear the IRQ line driven by a single device. The line is
+    // level triggered, so it stays active while any source holds it. The
+    // frame counter and DMC lines follow the APU's own flags, writes to
+    // them are ignored.
+    pub fn set_irq_line(&mut self, source: IrqSource, asserted: bool) {
+        let external: IrqSource = source - (IrqSource::FRAME_COUNTER | IrqSource::DMC);
+        self.irq_lines.set(external, asserted);
+    }
+
+    pub fn irq_lines(&self) -> IrqSource {
+        let mut lines: IrqSource = self.irq_lines;
+        lines.set(IrqSource::FRAME_COUNTER, self.apu.frame_irq());
+        lines.set(IrqSource::DMC, self.apu.dmc_irq());
+        lines
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        !self.irq_lines().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::joypad::JoypadButtons;
+    use crate::ppu::StatusRegister;
+    use crate::rom::test::{test_rom, RomBuilder};
+    use test_case::test_case;
+
+    #[test]
+    fn test_joypad_open_bus_bits() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.joypad_mut(1).set_button_pressed_status(JoypadButtons::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        assert_eq!(bus.peek(0x4016), 0x41);
+        assert_eq!(bus.mem_read(0x4016), 0x41);
+        assert_eq!(bus.mem_read(0x4016), 0x40);
+        assert_eq!(bus.mem_read(0x4017), 0x40);
+    }
+
+    #[test_case(0x0000)]
+    #[test_case(0x0800)]
+    #[test_case(0x1000)]
+    #[test_case(0x1800)]
+    fn test_ram_mirrors(write_base: u16) {
+        assert_eq!(RAM_MIRROR_MASK, 0x07FF);
+        let mut bus: Bus = Bus::new(test_rom());
+        for offset in [0x0000, 0x0123, 0x07FF] {
+            bus.mem_write(write_base + offset, offset as u8 ^ 0x5A);
+            for read_base in [0x0000, 0x0800, 0x1000, 0x1800] {
+                assert_eq!(bus.mem_read(read_base + offset), offset as u8 ^ 0x5A);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_range() {
+        let mut bus: Bus = Bus::new(test_rom());
+        for i in 0..16u16 {
+            bus.mem_write(i, (i as u8) * 3);
+        }
+        let expected: Vec<u8> = (0..16u8).map(|i| i * 3).collect();
+        assert_eq!(bus.read_range(0x0000, 16), expected);
+
+        // PRG is filled with 1s, then the dump carries on from zero page
+        let wrapped: Vec<u8> = bus.read_range(0xFFFE, 4);
+        assert_eq!(wrapped, vec![0x01, 0x01, 0x00, 0x03]);
+    }
+
+    #[test_case(0)]
+    #[test_case(3)]
+    #[should_panic(expected = "ports are 1 and 2")]
+    fn test_joypad_port_out_of_range(port: usize) {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.joypad_mut(port);
+    }
+
+    #[test]
+    fn test_read_range_has_no_side_effects() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.ppu_mut().status.insert(StatusRegister::VBLANK_STARTED);
+        bus.joypad_mut(1).set_button_pressed_status(JoypadButtons::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        bus.read_range(0x2000, 8);
+        bus.read_range(0x4016, 1);
+        assert_eq!(bus.read_range(0x4018, 16), vec![0; 16]);
+        assert!(bus.ppu().status.contains(StatusRegister::VBLANK_STARTED));
+        assert_eq!(bus.mem_read(0x4016) & 1, 1);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut bus: Bus = Bus::new(test_rom());
+        for (i, byte) in [0xDE, 0xAD, 0xBE, 0xEF].iter().enumerate() {
+            bus.mem_write(0x0123 + i as u16, *byte);
+            bus.mem_write(0x07FC + i as u16, *byte);
+            bus.mem_write(0x6100 + i as u16, *byte);
+        }
+        bus.mem_write(0x0200, 0xDE);
+        bus.mem_write(0x0201, 0xAD);
+
+        assert_eq!(bus.search(&[0xDE, 0xAD, 0xBE, 0xEF]), vec![0x0123, 0x07FC, 0x6100]);
+        assert_eq!(bus.search(&[0xDE, 0xAD]), vec![0x0123, 0x0200, 0x07FC, 0x6100]);
+        assert!(bus.search(&[0xEF, 0xDE]).is_empty());
+        assert!(bus.search(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_ppu_warmup_ignores_ctrl_writes() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.mem_write(0x2000, 0x90);
+        assert!(bus.ppu().ctrl.is_empty());
+        // The ignored write is still left on the data bus
+        assert_eq!(bus.mem_read(0x2000), 0x90);
+        // OAM is writable straight away
+        bus.mem_write(0x2003, 0x10);
+        assert_eq!(bus.ppu().oam_addr, 0x10);
+
+        while bus.cycles() < 29658 {
+            bus.mem_write(0x2000, 0x90);
+            assert!(bus.ppu().ctrl.is_empty());
+            bus.tick(2);
+        }
+        bus.mem_write(0x2000, 0x90);
+        assert_eq!(bus.ppu().ctrl.bits(), 0x90);
+    }
+
+    #[test]
+    fn test_axrom_single_screen_nametables() {
+        let mut prg: Vec<u8> = vec![0; 0x10000];
+        prg[0x8000] = 0x42;
+        let raw: Vec<u8> = RomBuilder::new().prg(prg).mapper(7).build();
+        let mut bus: Bus = Bus::new(Rom::new(&raw).unwrap());
+        assert_eq!(bus.mem_read(0x8000), 0);
+
+        // Second 32KB bank, upper nametable on every screen
+        bus.mem_write(0x8000, 0b1_0001);
+        assert_eq!(bus.mem_read(0x8000), 0x42);
+        for nametable in [0x2000, 0x2400, 0x2800, 0x2C00] {
+            assert_eq!(bus.ppu.mirror_vram_addr(nametable + 0x15), 0x415);
+        }
+
+        bus.mem_write(0xFFFF, 0);
+        assert_eq!(bus.ppu.mirror_vram_addr(0x2C15), 0x015);
+    }
+
+    #[test_case(0, 513; "even cycle")]
+    #[test_case(1, 514; "odd cycle")]
+    fn test_oam_dma(start_cycles: u8, expected_stall: usize) {
+        let mut bus: Bus = Bus::new(test_rom());
+        for i in 0..=0xFF {
+            bus.mem_write(0x0200 + i, i as u8 ^ 0x5A);
+        }
+        bus.tick(start_cycles);
+        bus.ppu.write_to_oam_addr(0x10);
+
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.ppu.oam[0x10], 0);
+        let before: usize = bus.cycles();
+        assert_eq!(bus.run_dma(), expected_stall);
+        assert_eq!(bus.cycles() - before, expected_stall);
+
+        // Copy starts at OAMADDR and wraps around
+        assert_eq!(bus.ppu.oam[0x10], 0x5A);
+        assert_eq!(bus.ppu.oam[0x0F], 0xFF ^ 0x5A);
+        assert_eq!(bus.run_dma(), 0);
+    }
+
+    // 1-byte DMC sample at $C000, waiting to be fetched
+    fn start_dmc(bus: &mut Bus) {
+        bus.mem_write(0x4010, 0x0F);
+        bus.mem_write(0x4012, 0x00);
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0x10);
+    }
+
+    #[test]
+    fn test_dmc_fetch_stall() {
+        let mut prg: Vec<u8> = vec![0; 0x4000];
+        prg[0] = 0xFF;
+        let raw: Vec<u8> = RomBuilder::new().prg(prg).build();
+        let mut bus: Bus = Bus::new(Rom::new(&raw).unwrap());
+        start_dmc(&mut bus);
+
+        let before: usize = bus.cycles();
+        assert_eq!(bus.run_dma(), 4);
+        assert_eq!(bus.cycles() - before, 4);
+        assert_eq!(bus.apu.dmc_sample_request(), None);
+        assert_eq!(bus.run_dma(), 0);
+
+        // The byte read from $C000 plays once the silent first byte ends
+        assert_eq!(bus.apu.peek_status() & 0x10, 0);
+        for _ in 0..54 * 9 {
+            bus.tick(1);
         }
+        assert!(bus.apu.output() > 0.0);
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.rom.prg.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
+    #[test]
+    fn test_dmc_fetch_is_a_bus_read() {
+        let mut prg: Vec<u8> = vec![0; 0x4000];
+        prg[0] = 0xFF;
+        let raw: Vec<u8> = RomBuilder::new().prg(prg).build();
+        let mut bus: Bus = Bus::new(Rom::new(&raw).unwrap());
+        start_dmc(&mut bus);
+
+        let accesses: Rc<RefCell<Vec<BusAccess>>> = Rc::new(RefCell::new(Vec::new()));
+        let log: Rc<RefCell<Vec<BusAccess>>> = accesses.clone();
+        bus.bus_trace = Some(Box::new(move |access| log.borrow_mut().push(access)));
+        bus.run_dma();
+        assert_eq!(*accesses.borrow(), vec![BusAccess { addr: 0xC000, value: 0xFF, is_write: false }]);
+    }
+
+    #[test]
+    fn test_dmc_fetch_during_oam_dma() {
+        let mut bus: Bus = Bus::new(test_rom());
+        start_dmc(&mut bus);
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.run_dma(), 513 + 2);
+    }
+
+    #[test]
+    fn test_master_clock_ratios() {
+        let mut bus: Bus = Bus::new(test_rom());
+        for _ in 0..11 {
+            bus.clock();
         }
-        self.rom.prg[addr as usize]
+        assert_eq!((bus.cycles(), bus.ppu.dot()), (0, 2));
+        bus.clock();
+        assert_eq!((bus.cycles(), bus.ppu.dot()), (1, 3));
+
+        bus.tick(2);
+        assert_eq!(bus.master_cycles(), 36);
+        assert_eq!((bus.cycles(), bus.ppu.dot()), (3, 9));
+
+        // Stopping part way through a CPU cycle leaves tick on master cycles
+        bus.clock();
+        bus.tick(1);
+        assert_eq!(bus.master_cycles(), 49);
+        assert_eq!((bus.cycles(), bus.ppu.dot()), (4, 12));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_deferred_ticks_paid_by_clock() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.defer_ticks = true;
+        bus.tick(2);
+        assert_eq!((bus.cycles(), bus.owed_master_cycles()), (0, 24));
+
+        bus.defer_ticks = false;
+        bus.clock();
+        assert_eq!(bus.owed_master_cycles(), 23);
+        bus.catch_up();
+        assert_eq!((bus.cycles(), bus.ppu.dot()), (2, 6));
+    }
+
+    #[test]
+    fn test_take_frame() {
+        let mut bus: Bus = Bus::new(test_rom());
+        assert!(bus.take_frame().is_none());
+
+        // A full frame is 341 * 262 PPU dots, just under 29781 CPU cycles
+        while bus.cycles() < 29778 {
+            bus.tick(3);
+            assert!(bus.take_frame().is_none());
+        }
+        bus.tick(3);
+        assert!(bus.take_frame().is_some());
+        assert!(bus.take_frame().is_none());
+    }
+
+    // Strobe the controllers and shift out all eight buttons of a port
+    fn read_joypad(bus: &mut Bus, addr: u16) -> u8 {
+        bus.mem_write(JOYPAD_1, 1);
+        bus.mem_write(JOYPAD_1, 0);
+        (0..8).fold(0, |buttons, bit| buttons | ((bus.mem_read(addr) & 1) << bit))
+    }
+
+    #[test]
+    fn test_frame_input_script() {
+        let mut bus: Bus = Bus::new(test_rom());
+        let script: Vec<(u8, u8)> = vec![(0x01, 0x80), (0x00, 0x00), (0x90, 0x03)];
+        let mut frames: std::vec::IntoIter<(u8, u8)> = script.clone().into_iter();
+        bus.on_frame_input = Some(Box::new(move || frames.next().unwrap_or((0, 0))));
+
+        // Input stays put mid-frame, whatever the script says next
+        assert_eq!(read_joypad(&mut bus, JOYPAD_1), 0);
+        for (port1, port2) in script {
+            while !bus.poll_frame() {
+                bus.tick(1);
+            }
+            assert_eq!(read_joypad(&mut bus, JOYPAD_1), port1);
+            assert_eq!(read_joypad(&mut bus, JOYPAD_2), port2);
+            bus.tick(50);
+            assert_eq!(read_joypad(&mut bus, JOYPAD_1), port1);
+        }
+    }
+
+    #[test]
+    fn test_irq_line_held_until_all_sources_clear() {
+        let mut bus: Bus = Bus::new(test_rom());
+        assert!(!bus.irq_pending());
+
+        // 4-step mode raises the frame IRQ at the end of each sequence
+        bus.mem_write(0x4017, 0x00);
+        bus.set_irq_line(IrqSource::MAPPER, true);
+        while !bus.apu.frame_irq() {
+            bus.tick(1);
+        }
+        assert_eq!(bus.irq_lines(), IrqSource::MAPPER | IrqSource::FRAME_COUNTER);
+
+        bus.set_irq_line(IrqSource::MAPPER, false);
+        assert!(bus.irq_pending());
+
+        // Ticking leaves the mapper's line alone, and $4015 acknowledges
+        // the frame IRQ
+        bus.set_irq_line(IrqSource::MAPPER, true);
+        bus.tick(10);
+        bus.mem_read(0x4015);
+        assert_eq!(bus.irq_lines(), IrqSource::MAPPER);
+    }
+
+    #[test]
+    fn test_apu_irq_lines_ignore_external_writes() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.set_irq_line(IrqSource::FRAME_COUNTER | IrqSource::DMC, true);
+        assert!(!bus.irq_pending());
+
+        bus.mem_write(0x4017, 0x00);
+        while !bus.apu.frame_irq() {
+            bus.tick(1);
+        }
+        bus.set_irq_line(IrqSource::FRAME_COUNTER, false);
+        assert!(bus.irq_pending());
+    }
+
+    #[test]
+    fn test_oam_registers_mirrored() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.mem_write(0x2003, 0x20);
+        bus.mem_write(0x2004, 0xAB);
+        bus.mem_write(0x200C, 0xCD);
+        bus.mem_write(0x3FFB, 0x20);
+        assert_eq!(bus.mem_read(0x2004), 0xAB);
+        bus.mem_write(0x2003, 0x21);
+        assert_eq!(bus.mem_read(0x3FFC), 0xCD);
+    }
+
+    #[test]
+    fn test_ram_snapshot_restore() {
+        let mut bus: Bus = Bus::new(test_rom());
+        for addr in 0..2048u16 {
+            bus.mem_write(addr, (addr * 7) as u8);
+        }
+        let snapshot: [u8; 2048] = bus.ram_snapshot();
+
+        bus.fill_ram(0);
+        assert_eq!(bus.mem_read(0x0123), 0);
+
+        bus.restore_ram(&snapshot);
+        for addr in 0..2048u16 {
+            assert_eq!(bus.mem_read(addr), (addr * 7) as u8);
+        }
+    }
+
+    #[test_case(0x2000)]
+    #[test_case(0x2001)]
+    #[test_case(0x2003)]
+    #[test_case(0x2005)]
+    #[test_case(0x2006)]
+    fn test_write_only_ppu_register_reads_latch(addr: u16) {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.mem_write(0x2000, 0x80);
+        assert_eq!(bus.mem_read(addr), 0x80);
+        assert_eq!(bus.mem_read(addr + 8), 0x80);
+
+        bus.mem_write(0x2003, 0x5A);
+        assert_eq!(bus.peek(addr), 0x5A);
+    }
+
+    #[test]
+    fn test_status_low_bits_from_latch() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.mem_write(0x2001, 0x1F);
+        assert_eq!(bus.mem_read(0x2002) & 0x1F, 0x1F);
+    }
+
+    #[test]
+    fn test_u16_little_endian() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.mem_write_u16(0x0010, 0x1234);
+        assert_eq!(bus.mem_read(0x0010), 0x34);
+        assert_eq!(bus.mem_read(0x0011), 0x12);
+        assert_eq!(bus.mem_read_u16(0x0010), 0x1234);
+        assert_eq!(bus.peek_u16(0x0010), 0x1234);
+    }
+
+    #[test]
+    fn test_u16_read_wraps_address_space() {
+        // Low byte from ROM at 0xFFFF, high byte from RAM at 0x0000
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.mem_write(0x0000, 0xAB);
+        assert_eq!(bus.mem_read_u16(0xFFFF), 0xAB01);
+        assert_eq!(bus.peek_u16(0xFFFF), 0xAB01);
+    }
+
+    #[test]
+    fn test_u16_write_wraps_address_space() {
+        let mut bus: Bus = Bus::new(test_rom());
+        bus.mem_write_u16(0x07FF, 0xBEEF);
+        bus.mem_write_u16(0xFFFF, 0x4200);
+        assert_eq!(bus.mem_read(0x07FF), 0xEF);
+        assert_eq!(bus.mem_read(0x0000), 0x42);
+    }
+
+    #[test_case(0x0000, MemRegion::Ram)]
+    #[test_case(0x1FFF, MemRegion::Ram)]
+    #[test_case(0x2001, MemRegion::PpuRegister)]
+    #[test_case(0x3FFF, MemRegion::PpuRegister)]
+    #[test_case(0x4015, MemRegion::ApuIo)]
+    #[test_case(0x4018, MemRegion::Unmapped)]
+    #[test_case(0x5FFF, MemRegion::Unmapped)]
+    #[test_case(0x6000, MemRegion::PrgRam)]
+    #[test_case(0x8000, MemRegion::PrgRom)]
+    #[test_case(0xFFFF, MemRegion::PrgRom)]
+    fn test_region(addr: u16, expected: MemRegion) {
+        assert_eq!(region(addr), expected);
+    }
+}
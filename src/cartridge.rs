@@ -0,0 +1,119 @@
+use crate::mapper;
+use crate::mapper::SharedMapper;
+use crate::rom::{Mirroring, Rom};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PRG_RAM_START: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Everything plugged into the cartridge slot: the mapper with its banks
+// and bank registers, plus the 8KB of PRG RAM at 0x6000. The bus and the
+// PPU share it, each going through its own side of the connector.
+pub type SharedCartridge = Rc<RefCell<Cartridge>>;
+
+pub struct Cartridge {
+    mapper: SharedMapper,
+    prg_ram: [u8; PRG_RAM_SIZE],
+}
+
+impl Cartridge {
+    pub fn new(rom: &Rom) -> Self {
+        Cartridge {
+            mapper: mapper::from_rom(rom),
+            prg_ram: [0; PRG_RAM_SIZE],
+        }
+    }
+
+    // CPU access to 0x6000-0xFFFF, reads have no side effects
+    pub fn read_cpu(&self, addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END => self.prg_ram[(addr - PRG_RAM_START) as usize],
+            _ => self.mapper.borrow().read_prg(addr),
+        }
+    }
+
+    pub fn write_cpu(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END => self.prg_ram[(addr - PRG_RAM_START) as usize] = data,
+            _ => self.mapper.borrow_mut().write_prg(addr, data),
+        }
+    }
+
+    // PPU access to the pattern tables at 0x0000-0x1FFF
+    pub fn read_ppu(&self, addr: u16) -> u8 {
+        self.mapper.borrow().read_chr(addr)
+    }
+
+    pub fn write_ppu(&mut self, addr: u16, data: u8) {
+        self.mapper.borrow_mut().write_chr(addr, data);
+    }
+
+    // Nametable layout when the mapper controls it rather than the header
+    pub fn mirroring(&self) -> Option<Mirroring> {
+        self.mapper.borrow().mirroring()
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    // Restore PRG RAM from a save, extra bytes are ignored
+    pub fn load_prg_ram(&mut self, data: &[u8]) {
+        let len: usize = data.len().min(PRG_RAM_SIZE);
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::RomBuilder;
+
+    fn cartridge(mapper: u8, prg: Vec<u8>, chr: Vec<u8>) -> Cartridge {
+        let raw: Vec<u8> = RomBuilder::new().prg(prg).chr(chr).mapper(mapper).build();
+        Cartridge::new(&Rom::new(&raw).unwrap())
+    }
+
+    #[test]
+    fn test_cpu_access_routing() {
+        let mut prg: Vec<u8> = vec![0; 0x4000];
+        prg[0x0010] = 0x42;
+        let mut cart: Cartridge = cartridge(0, prg, vec![0; 0x2000]);
+
+        // 16KB NROM shows up at both 0x8000 and 0xC000
+        assert_eq!(cart.read_cpu(0x8010), 0x42);
+        assert_eq!(cart.read_cpu(0xC010), 0x42);
+        cart.write_cpu(0x8010, 0x99);
+        assert_eq!(cart.read_cpu(0x8010), 0x42);
+
+        cart.write_cpu(0x6000, 0x12);
+        cart.write_cpu(0x7FFF, 0x34);
+        assert_eq!(cart.read_cpu(0x6000), 0x12);
+        assert_eq!(cart.prg_ram()[0x1FFF], 0x34);
+    }
+
+    #[test]
+    fn test_ppu_access_follows_mapper_banks() {
+        let mut chr: Vec<u8> = vec![0; 0x2000];
+        chr.extend(vec![1; 0x2000]);
+        let mut cart: Cartridge = cartridge(3, vec![0; 0x4000], chr);
+        assert_eq!(cart.read_ppu(0x0123), 0);
+
+        // CNROM bank select goes through the CPU side, and the PPU sees it
+        cart.write_cpu(0x8000, 1);
+        assert_eq!(cart.read_ppu(0x0123), 1);
+
+        // CHR ROM can't be written
+        cart.write_ppu(0x0123, 0x55);
+        assert_eq!(cart.read_ppu(0x0123), 1);
+    }
+
+    #[test]
+    fn test_chr_ram_writable() {
+        let mut cart: Cartridge = cartridge(0, vec![0; 0x4000], vec![]);
+        cart.write_ppu(0x1FFF, 0x55);
+        assert_eq!(cart.read_ppu(0x1FFF), 0x55);
+    }
+}
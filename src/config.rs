@@ -0,0 +1,87 @@
+use crate::console::TvSystem;
+use crate::joypad::{JoypadButtons, Key, KeyMap};
+
+// User settings that persist between runs. Key bindings are stored as
+// (key, button bits) pairs.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub struct Config {
+    pub key_bindings: Vec<(Key, u8)>,
+    pub tv_system: TvSystem,
+    pub ram_fill: u8,
+}
+
+impl Config {
+    pub fn key_map(&self) -> KeyMap<Key> {
+        let mut keymap: KeyMap<Key> = KeyMap::empty();
+        for (key, bits) in self.key_bindings.iter() {
+            keymap.bind(*key, JoypadButtons::from_bits_truncate(*bits));
+        }
+        keymap
+    }
+
+    pub fn set_key_map(&mut self, keymap: &KeyMap<Key>) {
+        self.key_bindings = keymap.bindings().map(|(key, button)| (*key, button.bits())).collect();
+    }
+
+    #[cfg(feature = "json")]
+    pub fn load_from_str(json: &str) -> Result<Config, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "json")]
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let json: String = serde_json::to_string_pretty(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut config: Config = Config {
+            key_bindings: vec![],
+            tv_system: TvSystem::Ntsc,
+            ram_fill: 0,
+        };
+        config.set_key_map(&KeyMap::default());
+        config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_key_map() {
+        let keymap: KeyMap<Key> = Config::default().key_map();
+        assert_eq!(keymap.button(&Key::Enter), Some(JoypadButtons::START));
+        assert_eq!(keymap.button(&Key::Letter('s')), Some(JoypadButtons::BUTTON_B));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_round_trip() {
+        let mut keymap: KeyMap<Key> = KeyMap::empty();
+        keymap.bind(Key::Letter('z'), JoypadButtons::BUTTON_A);
+        keymap.bind(Key::RightShift, JoypadButtons::SELECT);
+        let mut config: Config = Config {
+            key_bindings: vec![],
+            tv_system: TvSystem::Pal,
+            ram_fill: 0xFF,
+        };
+        config.set_key_map(&keymap);
+
+        let loaded: Config = Config::load_from_str(&config.to_string()).unwrap();
+        assert_eq!(loaded, config);
+        assert_eq!(loaded.key_map().button(&Key::Letter('z')), Some(JoypadButtons::BUTTON_A));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_load_error() {
+        assert!(Config::load_from_str("{").is_err());
+    }
+}
@@ -0,0 +1,788 @@
+use crate::bus::Bus;
+use crate::config::Config;
+use crate::cpu::CPU;
+use crate::frame::Frame;
+use crate::mem::Mem;
+#[cfg(feature = "profile")]
+use crate::profile::{self, ProfileStats};
+use crate::render;
+use crate::rom::Rom;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Controller bytes for ports 1 and 2 on each frame since a reset, tied to
+// the game they were recorded on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub rom_hash: String,
+    pub frames: Vec<(u8, u8)>,
+}
+
+pub struct Console {
+    pub cpu: CPU,
+    pub frame: Frame,
+    // Emulated frames per presented frame, above 1 fast-forwards
+    pub speed_multiplier: u32,
+    pub config: Config,
+    skipped_frames: u32,
+    audio: Vec<f32>,
+    has_battery: bool,
+    save_path: Option<PathBuf>,
+    rom_hash: String,
+    recording: Option<Vec<(u8, u8)>>,
+    #[cfg(feature = "profile")]
+    step_time: Duration,
+}
+
+impl Console {
+    pub fn new(rom: Rom) -> Self {
+        Console::with_config(rom, Config::default())
+    }
+
+    // Like new, but refuses carts whose mapper isn't implemented instead of
+    // running them as NROM
+    pub fn from_rom(rom: Rom) -> Result<Self, String> {
+        if !rom.is_mapper_supported() {
+            return Err(format!("Mapper {} is not supported.", rom.mapper));
+        }
+        Ok(Console::new(rom))
+    }
+
+    pub fn with_config(rom: Rom, config: Config) -> Self {
+        let has_battery: bool = rom.has_battery;
+        let rom_hash: String = rom.prg_chr_hash();
+        let mut bus: Bus = Bus::new(rom);
+        bus.fill_ram(config.ram_fill);
+        let mut cpu: CPU = CPU::new(bus);
+        cpu.reset();
+        Console {
+            cpu: cpu,
+            frame: Frame::new(),
+            speed_multiplier: 1,
+            config: config,
+            skipped_frames: 0,
+            audio: Vec::new(),
+            has_battery: has_battery,
+            save_path: None,
+            rom_hash: rom_hash,
+            recording: None,
+            #[cfg(feature = "profile")]
+            step_time: Duration::ZERO,
+        }
+    }
+
+    // Keep battery backed PRG RAM in a file. An existing save is loaded
+    // straight away, and PRG RAM is written back by save and on drop.
+    pub fn set_save_path<P: Into<PathBuf>>(&mut self, path: P) -> io::Result<()> {
+        let path: PathBuf = path.into();
+        if self.has_battery {
+            match fs::read(&path) {
+                Ok(data) => self.cpu.bus.load_sram(&data),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    // Write PRG RAM to the save file, carts without a battery have nothing
+    // to keep
+    pub fn save(&self) -> io::Result<()> {
+        match (&self.save_path, self.has_battery) {
+            (Some(path), true) => fs::write(path, self.cpu.bus.sram()),
+            _ => Ok(()),
+        }
+    }
+
+    // Same as pressing the reset button on the console
+    pub fn reset(&mut self) {
+        self.cpu.bus.reset();
+        self.cpu.reset();
+        self.skipped_frames = 0;
+    }
+
+    // Swap in another game. The old cart is saved first, then the console
+    // starts over as if powered on with the new one. Config, speed and CPU
+    // options carry over, the save path and start PC override don't. An
+    // unsupported mapper leaves the current game running.
+    pub fn insert_cartridge(&mut self, rom: Rom) -> Result<(), String> {
+        if !rom.is_mapper_supported() {
+            return Err(format!("Mapper {} is not supported.", rom.mapper));
+        }
+        let _ = self.save();
+        self.save_path = None;
+        self.has_battery = rom.has_battery;
+        self.rom_hash = rom.prg_chr_hash();
+        self.recording = None;
+
+        let mut bus: Bus = Bus::new(rom);
+        bus.fill_ram(self.config.ram_fill);
+        self.cpu.bus = bus;
+        self.cpu.clear_start_pc();
+        self.cpu.reset();
+        self.frame = Frame::new();
+        self.skipped_frames = 0;
+        self.audio.clear();
+        Ok(())
+    }
+
+    // Set every button on the first controller from a raw byte, see
+    // Joypad::set_state
+    pub fn set_joypad1_state(&mut self, buttons: u8) {
+        self.cpu.bus.joypad_mut(1).set_state(buttons);
+    }
+
+    // Reset and log the controllers at the end of every frame from here on.
+    // Work RAM survives the reset, so games have to initialise it for a
+    // movie to replay the same way.
+    pub fn start_recording(&mut self) {
+        self.cpu.bus.on_frame_input = None;
+        self.reset();
+        self.recording = Some(Vec::new());
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recording.take().map(|frames| Movie {
+            rom_hash: self.rom_hash.clone(),
+            frames: frames,
+        })
+    }
+
+    // Reset and drive the controllers from `movie`, one entry per frame.
+    // Both ports read as released once it runs out.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+        if movie.rom_hash != self.rom_hash {
+            return Err(format!("Movie was recorded on ROM {}, not {}.", movie.rom_hash, self.rom_hash));
+        }
+        self.recording = None;
+        self.reset();
+
+        let mut frames: std::vec::IntoIter<(u8, u8)> = movie.frames.into_iter();
+        let (port1, port2): (u8, u8) = frames.next().unwrap_or((0, 0));
+        self.cpu.bus.joypad_mut(1).set_state(port1);
+        self.cpu.bus.joypad_mut(2).set_state(port2);
+        self.cpu.bus.on_frame_input = Some(Box::new(move || frames.next().unwrap_or((0, 0))));
+        Ok(())
+    }
+
+    // Log the buttons the frame that just ended was played with
+    fn record_frame_input(&mut self) {
+        if let Some(frames) = self.recording.as_mut() {
+            let port1: u8 = self.cpu.bus.joypad_mut(1).buttons().bits();
+            let port2: u8 = self.cpu.bus.joypad_mut(2).buttons().bits();
+            frames.push((port1, port2));
+        }
+    }
+
+    // CHR byte as the PPU currently sees it through the cartridge mapper
+    pub fn chr_read(&self, addr: u16) -> u8 {
+        self.cpu.bus.ppu().chr_read(addr)
+    }
+
+    // Advance one master clock cycle. The CPU runs each instruction as soon
+    // as the rest of the system has caught up with the last one, then the
+    // PPU and APU are clocked through the cycles it took. Returns false
+    // once the CPU halts.
+    pub fn clock(&mut self) -> bool {
+        if self.cpu.bus.owed_master_cycles() == 0 {
+            self.cpu.bus.defer_ticks = true;
+            let running: bool = self.step();
+            self.cpu.bus.defer_ticks = false;
+            if !running {
+                return false;
+            }
+        }
+        self.cpu.bus.clock();
+        true
+    }
+
+    #[cfg(not(feature = "profile"))]
+    fn step(&mut self) -> bool {
+        self.cpu.bus.catch_up();
+        self.cpu.step()
+    }
+
+    #[cfg(feature = "profile")]
+    fn step(&mut self) -> bool {
+        self.cpu.bus.catch_up();
+        let cpu: &mut CPU = &mut self.cpu;
+        profile::timed(&mut self.step_time, || cpu.step())
+    }
+
+    // Time spent per component since the last reset_profile, CPU time
+    // excludes the PPU and APU ticks made inside each step
+    #[cfg(feature = "profile")]
+    pub fn profile_stats(&self) -> ProfileStats {
+        let devices: ProfileStats = self.cpu.bus.profile();
+        ProfileStats {
+            cpu: self.step_time.saturating_sub(devices.ppu + devices.apu),
+            ..devices
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    pub fn reset_profile(&mut self) {
+        self.cpu.bus.reset_profile();
+        self.step_time = Duration::ZERO;
+    }
+
+    // Run to the end of the next frame, returning it with the audio samples
+    // produced along the way. Stops early if the CPU halts.
+    pub fn run_frame(&mut self) -> (&Frame, &[f32]) {
+        self.audio.clear();
+        while self.step() {
+            if self.cpu.bus.poll_frame() {
+                self.record_frame_input();
+                render::render(self.cpu.bus.ppu(), &mut self.frame);
+                break;
+            }
+        }
+        self.cpu.bus.apu_mut().drain_samples(&mut self.audio);
+        (&self.frame, &self.audio)
+    }
+
+    // Run `n` frames and return the last one, audio is dropped
+    pub fn run_frames(&mut self, n: usize) -> &Frame {
+        for _ in 0..n {
+            self.run_frame();
+        }
+        &self.frame
+    }
+
+    // Run until the CPU halts, handing over the frame each time the PPU
+    // finishes one. With a speed multiplier only every Nth frame is rendered
+    // and presented.
+    pub fn run_with_frame_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&Frame),
+    {
+        loop {
+            if !self.step() {
+                return;
+            }
+
+            if self.cpu.bus.poll_frame() {
+                self.record_frame_input();
+                self.skipped_frames += 1;
+                if self.skipped_frames < self.speed_multiplier.max(1) {
+                    continue;
+                }
+                self.skipped_frames = 0;
+                render::render(self.cpu.bus.ppu(), &mut self.frame);
+                callback(&self.frame);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub enum TvSystem {
+    Ntsc,
+    Pal,
+}
+
+impl TvSystem {
+    pub fn master_clock_hz(&self) -> f64 {
+        match self {
+            TvSystem::Ntsc => 21_477_272.0,
+            TvSystem::Pal => 26_601_712.0,
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> f64 {
+        match self {
+            TvSystem::Ntsc => self.master_clock_hz() / 12.0,
+            TvSystem::Pal => self.master_clock_hz() / 16.0,
+        }
+    }
+
+    // 341 dots per scanline, PPU runs 3 (NTSC) or 3.2 (PAL) dots per CPU cycle
+    pub fn cpu_cycles_per_frame(&self) -> f64 {
+        match self {
+            TvSystem::Ntsc => 341.0 * 262.0 / 3.0,
+            TvSystem::Pal => 341.0 * 312.0 / 3.2,
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(self.cpu_cycles_per_frame() / self.cpu_clock_hz())
+    }
+}
+
+// Keeps presented frames in step with wall-clock time. Lives outside the
+// core loop so headless runs go as fast as possible.
+pub struct Pacer {
+    frame_duration: Duration,
+    next_frame: Option<Instant>,
+    last_frame: Option<Instant>,
+    fps: f64,
+}
+
+impl Pacer {
+    pub fn new(tv_system: TvSystem) -> Self {
+        Pacer {
+            frame_duration: tv_system.frame_duration(),
+            next_frame: None,
+            last_frame: None,
+            fps: 0.0,
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    // Frames per second measured between the last two calls to wait
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+    // Sleep until the next frame is due. Call once per presented frame.
+    pub fn wait(&mut self) {
+        let now: Instant = Instant::now();
+        let due: Instant = *self.next_frame.get_or_insert(now);
+        if due > now {
+            thread::sleep(due - now);
+        }
+
+        let presented: Instant = Instant::now();
+        if let Some(last) = self.last_frame {
+            let elapsed: f64 = (presented - last).as_secs_f64();
+            if elapsed > 0.0 {
+                self.fps = 1.0 / elapsed;
+            }
+        }
+        self.last_frame = Some(presented);
+
+        // Don't try to catch up after falling more than a frame behind
+        let next: Instant = due + self.frame_duration;
+        self.next_frame = Some(if next < presented { presented + self.frame_duration } else { next });
+    }
+}
+
+// Signature test ROMs write after the status byte once it is valid
+const TEST_ROM_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEST_ROM_RUNNING: u8 = 0x80;
+
+fn test_rom_finished(cpu: &CPU, done_addr: u16) -> bool {
+    let signed: bool = TEST_ROM_SIGNATURE
+        .iter()
+        .enumerate()
+        .all(|(i, byte)| cpu.peek(done_addr.wrapping_add(1 + i as u16)) == *byte);
+    signed && cpu.peek(done_addr) < TEST_ROM_RUNNING
+}
+
+// Run a blargg style test ROM until it reports a result at `done_addr`,
+// returning the status code and the zero terminated message after the
+// signature. Stops early if the CPU halts, and fails once `max_cycles` CPU
+// cycles pass without a result.
+pub fn run_test_rom(rom: Rom, done_addr: u16, max_cycles: usize) -> Result<(u8, String), String> {
+    let mut console: Console = Console::new(rom);
+    while !test_rom_finished(&console.cpu, done_addr) {
+        if console.cpu.bus.cycles() > max_cycles {
+            return Err(format!("No result at {:04x} after {} cycles.", done_addr, max_cycles));
+        }
+        if !console.cpu.step() {
+            break;
+        }
+    }
+
+    let mut message: Vec<u8> = vec![];
+    let mut addr: u16 = done_addr.wrapping_add(4);
+    while addr != 0 && console.cpu.peek(addr) != 0 {
+        message.push(console.cpu.peek(addr));
+        addr = addr.wrapping_add(1);
+    }
+    Ok((console.cpu.peek(done_addr), String::from_utf8_lossy(&message).to_string()))
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        // Nowhere to report a failure from here, call save to check it
+        let _ = self.save();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::{CPU_CLOCK_HZ, DEFAULT_SAMPLE_RATE};
+    use crate::frame::{HEIGHT, WIDTH};
+    use crate::rom::test::{create_rom, test_rom, RomBuilder, TestRom};
+    use test_case::test_case;
+
+    const CPU_CYCLES_PER_FRAME: usize = 341 * 262 / 3;
+
+    fn load_countdown_loop(console: &mut Console, passes: u8) {
+        // Nested DEX/DEY countdown loops repeated `passes` times, then BRK
+        let program: Vec<u8> = vec![
+            0xA9, passes, 0x85, 0x10, 0xA0, 0x00, 0xA2, 0x00, 0xCA, 0xD0, 0xFD, 0x88, 0xD0,
+            0xF8, 0xC6, 0x10, 0xD0, 0xF2, 0x00,
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            console.cpu.mem_write(0x0600 + i as u16, *byte);
+        }
+        console.cpu.program_counter = 0x0600;
+    }
+
+    fn save_file(name: &str) -> PathBuf {
+        let path: PathBuf = std::env::temp_dir().join(format!("nes-emulator-{}-{}.sav", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn battery_rom(battery: bool) -> Rom {
+        Rom::new(&RomBuilder::new().battery(battery).build()).unwrap()
+    }
+
+    #[test]
+    fn test_battery_save_round_trip() {
+        let path: PathBuf = save_file("round-trip");
+        let mut console: Console = Console::new(battery_rom(true));
+        console.set_save_path(&path).unwrap();
+        console.cpu.mem_write(0x6000, 0x12);
+        console.cpu.mem_write(0x7FFF, 0x34);
+        drop(console);
+
+        let saved: Vec<u8> = fs::read(&path).unwrap();
+        assert_eq!(saved.len(), 0x2000);
+        assert_eq!((saved[0], saved[0x1FFF]), (0x12, 0x34));
+
+        let mut console: Console = Console::new(battery_rom(true));
+        console.set_save_path(&path).unwrap();
+        assert_eq!(console.cpu.mem_read(0x6000), 0x12);
+        assert_eq!(console.cpu.mem_read(0x7FFF), 0x34);
+
+        console.cpu.mem_write(0x6001, 0x56);
+        console.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap()[1], 0x56);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_no_save_without_battery() {
+        let path: PathBuf = save_file("no-battery");
+        let mut console: Console = Console::new(battery_rom(false));
+        console.set_save_path(&path).unwrap();
+        console.cpu.mem_write(0x6000, 0x12);
+        drop(console);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_frame_callback_ntsc_rate() {
+        let mut console: Console = Console::new(test_rom());
+        load_countdown_loop(&mut console, 1);
+
+        let mut frames: usize = 0;
+        console.run_with_frame_callback(|_| frames += 1);
+
+        let expected: usize = console.cpu.bus.cycles() / CPU_CYCLES_PER_FRAME;
+        assert!(expected >= 8);
+        assert!(frames + 1 >= expected && frames <= expected + 1);
+    }
+
+    #[test]
+    fn test_speed_multiplier_skips_frames() {
+        let mut console: Console = Console::new(test_rom());
+        load_countdown_loop(&mut console, 4);
+        console.speed_multiplier = 4;
+
+        let mut presented: usize = 0;
+        console.run_with_frame_callback(|_| presented += 1);
+
+        let emulated: usize = console.cpu.bus.cycles() / CPU_CYCLES_PER_FRAME;
+        assert!(presented >= 8);
+        assert!(presented.abs_diff(emulated / 4) <= 1);
+    }
+
+    #[test]
+    fn test_run_frame_sizes() {
+        let mut console: Console = Console::new(test_rom());
+        load_countdown_loop(&mut console, 4);
+
+        for _ in 0..2 {
+            let start: usize = console.cpu.bus.cycles();
+            let samples: usize = console.run_frame().1.len();
+            let cycles: usize = console.cpu.bus.cycles() - start;
+            assert!(cycles.abs_diff(CPU_CYCLES_PER_FRAME) < 8);
+
+            // 29781 cycles at 44.1kHz is just over 733 samples
+            let expected: f64 = cycles as f64 * DEFAULT_SAMPLE_RATE as f64 / CPU_CLOCK_HZ;
+            assert!((samples as f64 - expected).abs() <= 1.0, "{} samples", samples);
+            assert_eq!(console.frame.data.len(), WIDTH * HEIGHT * 3);
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn test_profile_stats() {
+        let mut console: Console = Console::new(test_rom());
+        load_countdown_loop(&mut console, 4);
+        console.reset_profile();
+
+        let start: Instant = Instant::now();
+        for _ in 0..3 {
+            console.run_frame();
+        }
+        let elapsed: Duration = start.elapsed();
+
+        let stats: ProfileStats = console.profile_stats();
+        assert_eq!(stats.frames, 3);
+        assert!(stats.cpu > Duration::ZERO);
+        assert!(stats.ppu > Duration::ZERO);
+        assert!(stats.apu > Duration::ZERO);
+        assert!(stats.total() <= elapsed);
+    }
+
+    #[test]
+    fn test_clock_keeps_ppu_in_step_with_cpu() {
+        let mut console: Console = Console::new(test_rom());
+        load_countdown_loop(&mut console, 4);
+        let start_dots: usize = console.cpu.bus.ppu().scanline() as usize * 341 + console.cpu.bus.ppu().dot();
+
+        // 100 CPU cycles whatever the instruction boundaries
+        for _ in 0..1200 {
+            assert!(console.clock());
+        }
+        let dots: usize = console.cpu.bus.ppu().scanline() as usize * 341 + console.cpu.bus.ppu().dot();
+        assert_eq!(console.cpu.bus.cycles(), 100);
+        assert_eq!(dots - start_dots, 300);
+        assert_eq!(console.cpu.bus.master_cycles(), 1200);
+
+        // Switching back to whole frames pays off the running instruction first
+        console.run_frame();
+        assert_eq!(console.cpu.bus.owed_master_cycles(), 0);
+    }
+
+    #[test]
+    fn test_run_frames() {
+        let mut console: Console = Console::new(test_rom());
+        load_countdown_loop(&mut console, 4);
+
+        let len: usize = console.run_frames(3).data.len();
+        assert_eq!(len, WIDTH * HEIGHT * 3);
+        assert!(console.cpu.bus.cycles().abs_diff(3 * CPU_CYCLES_PER_FRAME) < 8);
+    }
+
+    #[test]
+    fn test_set_joypad1_state() {
+        let mut console: Console = Console::new(test_rom());
+        console.set_joypad1_state(0b1000_0001);
+        console.cpu.mem_write(0x4016, 1);
+        console.cpu.mem_write(0x4016, 0);
+
+        let reads: Vec<u8> = (0..8).map(|_| console.cpu.mem_read(0x4016) & 1).collect();
+        assert_eq!(reads, vec![1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_reset_clears_ppu_registers() {
+        let mut console: Console = Console::new(test_rom());
+        console.cpu.bus.ppu_mut().skip_warmup();
+        console.cpu.mem_write(0x2000, 0x90);
+        console.cpu.mem_write(0x2001, 0x1E);
+        console.cpu.mem_write(0x0010, 0x42);
+        assert_eq!(console.cpu.bus.ppu().ctrl.bits(), 0x90);
+        assert_eq!(console.cpu.bus.ppu().mask.bits(), 0x1E);
+
+        console.cpu.program_counter = 0x0600;
+        console.reset();
+        assert!(console.cpu.bus.ppu().ctrl.is_empty());
+        assert!(console.cpu.bus.ppu().mask.is_empty());
+        assert_eq!(console.cpu.bus.ppu().scanline(), 0);
+        assert!(console.cpu.bus.ppu().warming_up());
+        assert_eq!(console.cpu.program_counter, 0x0101);
+
+        // Work RAM survives a reset
+        assert_eq!(console.cpu.mem_read(0x0010), 0x42);
+    }
+
+    #[test]
+    fn test_config_ram_fill() {
+        let config: Config = Config {
+            ram_fill: 0xFF,
+            ..Config::default()
+        };
+        let mut console: Console = Console::with_config(test_rom(), config);
+        assert_eq!(console.cpu.mem_read(0x0000), 0xFF);
+        assert_eq!(console.cpu.mem_read(0x07FF), 0xFF);
+    }
+
+    // Polls controller 1 in a loop, keeps a running total of the buttons
+    // seen and shows it as the backdrop colour
+    fn input_rom() -> Rom {
+        let source: &str = "LDA #$00\nSTA $01\n\
+            poll: LDA #$01\nSTA $4016\nLDA #$00\nSTA $4016\nLDX #$08\n\
+            bit: LDA $4016\nLSR A\nROL $00\nDEX\nBNE bit\n\
+            LDA $00\nCLC\nADC $01\nSTA $01\n\
+            LDA #$3F\nSTA $2006\nLDA #$00\nSTA $2006\nLDA $01\nAND #$3F\nSTA $2007\n\
+            JMP poll";
+        let mut prg: Vec<u8> = crate::asm::assemble(source, 0x8000).unwrap();
+        prg.resize(0x4000, 0);
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0x80;
+        Rom::new(&RomBuilder::new().prg(prg).build()).unwrap()
+    }
+
+    #[test]
+    fn test_movie_replays_same_frames() {
+        let mut console: Console = Console::new(input_rom());
+        console.run_frames(2);
+
+        console.start_recording();
+        for buttons in [0x01, 0x01, 0x90, 0x00, 0x42, 0x08] {
+            console.set_joypad1_state(buttons);
+            console.run_frame();
+        }
+        let movie: Movie = console.stop_recording().unwrap();
+        let recorded: u64 = console.frame.hash();
+        assert_eq!(movie.frames, vec![(0x01, 0), (0x01, 0), (0x90, 0), (0x00, 0), (0x42, 0), (0x08, 0)]);
+        assert_eq!(console.stop_recording(), None);
+
+        console.set_joypad1_state(0xFF);
+        console.play_movie(movie.clone()).unwrap();
+        assert_eq!(console.run_frames(6).hash(), recorded);
+
+        // Other input ends up on another colour
+        let mut other: Movie = movie.clone();
+        other.frames[4].0 = 0x02;
+        console.play_movie(other).unwrap();
+        assert_ne!(console.run_frames(6).hash(), recorded);
+    }
+
+    #[test]
+    fn test_movie_for_other_rom_rejected() {
+        let mut console: Console = Console::new(input_rom());
+        let movie: Movie = Movie { rom_hash: test_rom().prg_chr_hash(), frames: vec![(1, 0)] };
+        assert!(console.play_movie(movie).is_err());
+    }
+
+    #[test]
+    fn test_from_rom_rejects_unsupported_mapper() {
+        let rom: Rom = Rom::new(&RomBuilder::new().mapper(4).build()).unwrap();
+        assert!(!rom.is_mapper_supported());
+        assert_eq!(Console::from_rom(rom).err(), Some(String::from("Mapper 4 is not supported.")));
+
+        let rom: Rom = Rom::new(&RomBuilder::new().mapper(1).build()).unwrap();
+        assert!(Console::from_rom(rom).is_ok());
+    }
+
+    #[test]
+    fn test_insert_cartridge() {
+        let config: Config = Config {
+            ram_fill: 0xFF,
+            ..Config::default()
+        };
+        let mut console: Console = Console::with_config(test_rom(), config.clone());
+        console.speed_multiplier = 2;
+        console.cpu.mem_write(0x0010, 0x42);
+        console.cpu.set_start_pc(0x8000);
+        assert_eq!(console.cpu.program_counter, 0x0101);
+
+        // Reset vector $C000 in a 16KB NROM image
+        let mut prg: Vec<u8> = vec![0xEA; 0x4000];
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0xC0;
+        console.insert_cartridge(Rom::new(&RomBuilder::new().prg(prg).build()).unwrap()).unwrap();
+
+        assert_eq!(console.cpu.program_counter, 0xC000);
+        assert_eq!(console.cpu.mem_read(0xC000), 0xEA);
+        assert_eq!(console.cpu.mem_read(0x0010), 0xFF);
+        assert_eq!(console.speed_multiplier, 2);
+        assert_eq!(console.config, config);
+    }
+
+    #[test]
+    fn test_insert_cartridge_rejects_unsupported_mapper() {
+        let mut console: Console = Console::new(test_rom());
+        console.cpu.mem_write(0x0010, 0x42);
+        let rom: Rom = Rom::new(&RomBuilder::new().mapper(4).build()).unwrap();
+        assert_eq!(console.insert_cartridge(rom), Err(String::from("Mapper 4 is not supported.")));
+        assert_eq!(console.cpu.mem_read(0x0010), 0x42);
+    }
+
+    #[test]
+    fn test_chr_read_follows_cnrom_bank() {
+        let mut chr: Vec<u8> = vec![];
+        for bank in 0..4 {
+            chr.extend(vec![bank as u8; 0x2000]);
+        }
+        let raw: Vec<u8> = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x04, 0x30, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![0; 0x4000],
+            chr_rom: chr,
+        });
+        let mut console: Console = Console::new(Rom::new(&raw).unwrap());
+        assert_eq!(console.chr_read(0x0010), 0);
+
+        console.cpu.mem_write(0x8000, 2);
+        assert_eq!(console.chr_read(0x0010), 2);
+        assert_eq!(console.chr_read(0x1FF0), 2);
+    }
+
+    // NROM image that stores `status` and `message` the way blargg ROMs do
+    fn status_rom(status: u8, message: &str) -> Rom {
+        let mut program: Vec<u8> = vec![0xA9, 0x80, 0x8D, 0x00, 0x60];
+        let mut bytes: Vec<u8> = vec![0xDE, 0xB0, 0x61];
+        bytes.extend(message.bytes());
+        bytes.push(0);
+        for (i, byte) in bytes.iter().enumerate() {
+            let addr: u16 = 0x6001 + i as u16;
+            program.extend([0xA9, *byte, 0x8D, addr as u8, (addr >> 8) as u8]);
+        }
+        program.extend([0xA9, status, 0x8D, 0x00, 0x60]);
+
+        // Spin on JMP once finished
+        let end: u16 = 0x8000 + program.len() as u16;
+        program.extend([0x4C, end as u8, (end >> 8) as u8]);
+
+        let mut prg: Vec<u8> = vec![0; 0x4000];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x3FFD] = 0x80;
+        let raw: Vec<u8> = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: prg,
+            chr_rom: vec![0; 0x2000],
+        });
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test_case(0x00, "Passed\n")]
+    #[test_case(0x03, "Failed #3")]
+    fn test_run_test_rom(status: u8, message: &str) {
+        let (code, text): (u8, String) = run_test_rom(status_rom(status, message), 0x6000, 10_000).unwrap();
+        assert_eq!(code, status);
+        assert_eq!(text, message);
+    }
+
+    #[test]
+    fn test_run_test_rom_gives_up() {
+        // JMP to itself without ever writing a status
+        let mut prg: Vec<u8> = vec![0; 0x4000];
+        prg[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        prg[0x3FFD] = 0x80;
+        let raw: Vec<u8> = RomBuilder::new().prg(prg).chr(vec![0; 0x2000]).build();
+        let result: Result<(u8, String), String> = run_test_rom(Rom::new(&raw).unwrap(), 0x6000, 1000);
+        assert_eq!(result, Err("No result at 6000 after 1000 cycles.".to_string()));
+    }
+
+    #[test_case(TvSystem::Ntsc, 16.639)]
+    #[test_case(TvSystem::Pal, 19.997)]
+    fn test_pacer_frame_duration(tv_system: TvSystem, expected_ms: f64) {
+        let pacer: Pacer = Pacer::new(tv_system);
+        let millis: f64 = pacer.frame_duration().as_secs_f64() * 1000.0;
+        assert!((millis - expected_ms).abs() < 0.001, "{} ms", millis);
+    }
+}
@@ -1,13 +1,46 @@
-use std::collections::HashMap;
+use crate::asm;
 use crate::opcodes;
 use crate::opcodes::AddressingMode;
 use crate::bus::Bus;
 use crate::mem::Mem;
+use crate::trace;
+use std::collections::{HashSet, VecDeque};
 
 const PRG_REF: u16 = 0xFFFC;
 const PRG_START: u16 = 0x8000;
 const STACK_START: u8 = 0x00FD;
 const STACK_END: u16 = 0x0100;
+const ATX_MAGIC: u8 = 0xEE;
+const HISTORY_LEN: usize = 256;
+// A, X, Y, SP, status, PC and the bus cycle count as a u64, little endian
+pub const CPU_STATE_LEN: usize = 15;
+
+mod interrupt {
+    pub struct Interrupt {
+        pub vector_addr: u16,
+        pub brk_flag: bool,
+        pub cycles: u8,
+    }
+
+    pub const NMI: Interrupt = Interrupt {
+        vector_addr: 0xFFFA,
+        brk_flag: false,
+        cycles: 7,
+    };
+
+    pub const IRQ: Interrupt = Interrupt {
+        vector_addr: 0xFFFE,
+        brk_flag: false,
+        cycles: 7,
+    };
+
+    // Shares the IRQ vector, its cycles come from the opcode table
+    pub const BRK: Interrupt = Interrupt {
+        vector_addr: 0xFFFE,
+        brk_flag: true,
+        cycles: 7,
+    };
+}
 
 bitflags! {
     pub struct CPUFlags: u8 {
@@ -22,6 +55,122 @@ bitflags! {
     }
 }
 
+// Direction the stack pointer wrapped in
+#[cfg(debug_assertions)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StackWrap {
+    Overflow,
+    Underflow,
+}
+
+// Reasons step can refuse to run the next instruction
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StepError {
+    IllegalOpcode(u8),
+}
+
+// How step_over stopped
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StepOver {
+    // The instruction, or the whole call, finished and the CPU can go on
+    Done,
+    // The CPU halted on the way
+    Halted,
+    // The subroutine used up the instruction budget without returning
+    OutOfBudget,
+}
+
+// Cycles spent in each kind of work, only counted once enabled
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct CycleBreakdown {
+    // Loads, arithmetic and register-only instructions
+    pub reads: usize,
+    // Stores, pushes and read-modify-writes
+    pub writes: usize,
+    // Branches, jumps, calls and returns
+    pub branches: usize,
+    // Entering NMI, IRQ and BRK handlers
+    pub interrupts: usize,
+    // Stalled while DMA has the bus
+    pub dma: usize,
+}
+
+impl CycleBreakdown {
+    pub fn total(&self) -> usize {
+        self.reads + self.writes + self.branches + self.interrupts + self.dma
+    }
+
+    fn add(&mut self, opcode: &opcodes::OpCode, cycles: u8) {
+        let cycles: usize = cycles as usize;
+        match opcode.operation {
+            "BRK" => self.interrupts += cycles,
+            "BPL" | "BMI" | "BVC" | "BVS" | "BCC" | "BCS" | "BNE" | "BEQ"
+            | "JMP" | "JSR" | "RTS" | "RTI" => self.branches += cycles,
+            // Shifts on the accumulator don't touch memory
+            "ASL" | "LSR" | "ROL" | "ROR" if opcode.mode == AddressingMode::NoneAddressing => self.reads += cycles,
+            "STA" | "STX" | "STY" | "PHA" | "PHP" | "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC"
+            | "*SAX" | "*AHX" | "*SHX" | "*SHY" | "*TAS" | "*DCP" | "*ISB" | "*SLO" | "*RLA"
+            | "*SRE" | "*RRA" => self.writes += cycles,
+            _ => self.reads += cycles,
+        }
+    }
+}
+
+fn read_u16(read: &mut dyn FnMut(u16) -> u8, addr: u16) -> u16 {
+    let lo: u8 = read(addr);
+    let hi: u8 = read(addr.wrapping_add(1));
+    (hi as u16) << 8 | (lo as u16)
+}
+
+// Resolve the effective address of an operand at `curr_addr`, fetching
+// operand and pointer bytes through `read`
+fn decode_addr(mode: &AddressingMode, curr_addr: u16, register_x: u8, register_y: u8, read: &mut dyn FnMut(u16) -> u8) -> u16 {
+    match mode {
+        AddressingMode::ZeroPage => read(curr_addr) as u16,
+        AddressingMode::ZeroPage_X => {
+            let base: u8 = read(curr_addr);
+            let addr: u16 = base.wrapping_add(register_x) as u16;
+            addr
+        },
+        AddressingMode::ZeroPage_Y => {
+            let base: u8 = read(curr_addr);
+            let addr: u16 = base.wrapping_add(register_y) as u16;
+            addr
+        },
+        AddressingMode::Absolute => read_u16(read, curr_addr),
+        AddressingMode::Absolute_X => {
+            let base: u16 = read_u16(read, curr_addr);
+            let addr: u16 = base.wrapping_add(register_x as u16);
+            addr
+        },
+        AddressingMode::Absolute_Y => {
+            let base: u16 = read_u16(read, curr_addr);
+            let addr: u16 = base.wrapping_add(register_y as u16);
+            addr
+        },
+        AddressingMode::Indirect => {
+            let base: u16 = read_u16(read, curr_addr);
+            read_u16(read, base)
+        },
+        AddressingMode::Indirect_X => {
+            let base: u8 = read(curr_addr);
+            let ptr: u8 = base.wrapping_add(register_x);
+            let lo: u8 = read(ptr as u16);
+            let hi: u8 = read(ptr.wrapping_add(1) as u16);
+            (hi as u16) << 8 | (lo as u16)
+        }
+        AddressingMode::Indirect_Y => {
+            let base: u8 = read(curr_addr);
+            let lo: u8 = read(base as u16);
+            let hi: u8 = read(base.wrapping_add(1) as u16);
+            let indirect_base: u16 = (hi as u16) << 8 | (lo as u16);
+            let res: u16 = indirect_base.wrapping_add(register_y as u16);
+            res
+        }
+        _ => 0,
+    }
+}
+
 pub struct CPU {
     pub stack_ptr: u8,
     pub accumulator: u8,
@@ -30,16 +179,41 @@ pub struct CPU {
     pub status: CPUFlags,
     pub program_counter: u16,
     pub bus: Bus,
+    // Unstable constant ORed into A by ATX (0xAB), 0xEE or 0x00 depending
+    // on the chip
+    pub atx_magic: u8,
+    // The 2A03 has the decimal flag but no BCD arithmetic, set this to
+    // run ADC/SBC as a stock 6502 would
+    pub decimal_enabled: bool,
+    // Clear to reject undocumented opcodes, for checking code is portable
+    // to other 6502s
+    pub allow_illegal: bool,
+    // Stop running at BRK, which test programs use to end. Clear it to take
+    // the IRQ vector like the hardware does.
+    pub brk_halts: bool,
+    start_pc: Option<u16>,
+    // Cycles the current instruction spends beyond its base count
+    page_crossed: bool,
+    branch_cycles: u8,
+    coverage: Option<HashSet<u16>>,
+    history: Option<VecDeque<(u16, u8)>>,
+    cycle_breakdown: Option<CycleBreakdown>,
+    #[cfg(debug_assertions)]
+    stack_wrap_hook: Option<Box<dyn FnMut(StackWrap)>>,
 }
 
 
 impl Mem for CPU {
 
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
 
-    fn mem_read_u16(&self, addr: u16) -> u16 {
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    fn mem_read_u16(&mut self, addr: u16) -> u16 {
         self.bus.mem_read_u16(addr)
     }
 
@@ -64,65 +238,203 @@ impl CPU {
             status: CPUFlags::from_bits_truncate(0b0010_0100),
             program_counter: PRG_START,
             bus: bus,
+            atx_magic: ATX_MAGIC,
+            decimal_enabled: false,
+            allow_illegal: true,
+            brk_halts: true,
+            start_pc: None,
+            page_crossed: false,
+            branch_cycles: 0,
+            coverage: None,
+            history: None,
+            cycle_breakdown: None,
+            #[cfg(debug_assertions)]
+            stack_wrap_hook: None,
         }
     }
 
-    pub fn get_non_immediate_addr(&self, mode: &AddressingMode, curr_addr: u16) -> u16 {
-        match mode {
-            AddressingMode::ZeroPage => self.mem_read(curr_addr) as u16,
-            AddressingMode::ZeroPage_X => {
-                let base: u8 = self.mem_read(curr_addr);
-                let addr: u16 = base.wrapping_add(self.register_x) as u16;
-                addr
-            },
-            AddressingMode::ZeroPage_Y => {
-                let base: u8 = self.mem_read(curr_addr);
-                let addr: u16 = base.wrapping_add(self.register_y) as u16;
-                addr
-            },
-            AddressingMode::Absolute => self.mem_read_u16(curr_addr),
-            AddressingMode::Absolute_X => {
-                let base: u16 = self.mem_read_u16(curr_addr);
-                let addr: u16 = base.wrapping_add(self.register_x as u16);
-                addr
-            },
-            AddressingMode::Absolute_Y => {
-                let base: u16 = self.mem_read_u16(curr_addr);
-                let addr: u16 = base.wrapping_add(self.register_y as u16);
-                addr
-            },
-            AddressingMode::Indirect => {
-                let base: u16 = self.mem_read_u16(curr_addr);
-                self.mem_read_u16(base)
-            },
-            AddressingMode::Indirect_X => {
-                let base: u8 = self.mem_read(curr_addr);
-                let ptr: u8 = base.wrapping_add(self.register_x);
-                let lo: u8 = self.mem_read(ptr as u16);
-                let hi: u8 = self.mem_read(ptr.wrapping_add(1) as u16);
-                (hi as u16) << 8 | (lo as u16)
+    // Start recording the address of every executed instruction
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(HashSet::new());
+        }
+    }
+
+    // Addresses executed since coverage was enabled
+    pub fn covered(&self) -> Option<&HashSet<u16>> {
+        self.coverage.as_ref()
+    }
+
+    // Address and disassembly of the instruction about to run
+    pub fn peek_instruction(&self) -> (u16, String) {
+        (self.program_counter, trace::disassemble(self, self.program_counter))
+    }
+
+    // Registers and cycle count in a fixed layout, see CPU_STATE_LEN
+    pub fn serialize(&self) -> [u8; CPU_STATE_LEN] {
+        let mut state: [u8; CPU_STATE_LEN] = [0; CPU_STATE_LEN];
+        state[0] = self.accumulator;
+        state[1] = self.register_x;
+        state[2] = self.register_y;
+        state[3] = self.stack_ptr;
+        state[4] = self.status.bits();
+        state[5..7].copy_from_slice(&self.program_counter.to_le_bytes());
+        state[7..15].copy_from_slice(&(self.bus.cycles() as u64).to_le_bytes());
+        state
+    }
+
+    pub fn deserialize(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != CPU_STATE_LEN {
+            return Err(format!("CPU state is {} bytes, expected {}.", state.len(), CPU_STATE_LEN));
+        }
+        self.accumulator = state[0];
+        self.register_x = state[1];
+        self.register_y = state[2];
+        self.stack_ptr = state[3];
+        self.status = CPUFlags::from_bits_truncate(state[4]);
+        self.program_counter = u16::from_le_bytes([state[5], state[6]]);
+        let mut cycles: [u8; 8] = [0; 8];
+        cycles.copy_from_slice(&state[7..15]);
+        self.bus.set_cycles(u64::from_le_bytes(cycles) as usize);
+        Ok(())
+    }
+
+    // Disassembly around `center` for a debugger: up to `before`
+    // instructions leading up to it, then `after` instructions starting at
+    // it. Instructions are variable length, so the ones before are a guess:
+    // the furthest start that decodes forward onto `center` wins.
+    pub fn disasm_window(&self, center: u16, before: usize, after: usize) -> Vec<(u16, String)> {
+        let mut starts: Vec<u16> = Vec::new();
+        for back in (1..=before as u16 * 3).rev() {
+            let mut addr: u16 = center.wrapping_sub(back);
+            let mut path: Vec<u16> = Vec::new();
+            while center.wrapping_sub(addr) <= back && addr != center {
+                path.push(addr);
+                addr = addr.wrapping_add(self.instruction_bytes(addr).1 as u16);
             }
-            AddressingMode::Indirect_Y => {
-                let base: u8 = self.mem_read(curr_addr);
-                let lo: u8 = self.mem_read(base as u16);
-                let hi: u8 = self.mem_read(base.wrapping_add(1) as u16);
-                let indirect_base: u16 = (hi as u16) << 8 | (lo as u16);
-                let res: u16 = indirect_base.wrapping_add(self.register_y as u16);
-                res
+            if addr == center {
+                starts = path;
+                break;
             }
-            _ => 0,
         }
+        let skip: usize = starts.len().saturating_sub(before);
+        starts.drain(..skip);
+
+        let mut addr: u16 = center;
+        for _ in 0..after {
+            starts.push(addr);
+            addr = addr.wrapping_add(self.instruction_bytes(addr).1 as u16);
+        }
+        starts.into_iter().map(|addr| (addr, trace::disassemble(self, addr))).collect()
     }
 
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    // Opcode and operand bytes of the instruction at `addr`, unused bytes
+    // are 0, along with its length
+    pub fn instruction_bytes(&self, addr: u16) -> ([u8; 3], u8) {
+        let code: u8 = self.peek(addr);
+        let len: u8 = opcodes::OPCODE_TABLE[code as usize].map_or(1, |op| op.len);
+        let mut bytes: [u8; 3] = [code, 0, 0];
+        for i in 1..len {
+            bytes[i as usize] = self.peek(addr.wrapping_add(i as u16));
+        }
+        (bytes, len)
+    }
+
+    // Memory address the instruction at PC will operate on, resolved with
+    // the current registers. None when there's no memory operand.
+    pub fn current_effective_address(&self) -> Option<u16> {
+        let code: u8 = self.peek(self.program_counter);
+        let opcode: &opcodes::OpCode = opcodes::OPCODE_TABLE[code as usize]?;
+        match opcode.mode {
+            AddressingMode::NoneAddressing | AddressingMode::Immediate => None,
+            _ => Some(self.get_non_immediate_addr(&opcode.mode, self.program_counter.wrapping_add(1))),
+        }
+    }
+
+    // Start keeping the (pc, opcode) of the last 256 executed instructions
+    pub fn enable_history(&mut self) {
+        if self.history.is_none() {
+            self.history = Some(VecDeque::with_capacity(HISTORY_LEN));
+        }
+    }
+
+    // Recorded instructions, newest first
+    pub fn history(&self) -> Vec<(u16, u8)> {
+        match self.history.as_ref() {
+            Some(history) => history.iter().rev().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Start sorting executed cycles into reads, writes, branches and
+    // interrupts
+    pub fn enable_cycle_breakdown(&mut self) {
+        if self.cycle_breakdown.is_none() {
+            self.cycle_breakdown = Some(CycleBreakdown::default());
+        }
+    }
+
+    // Cycles counted since the breakdown was enabled
+    pub fn cycle_breakdown(&self) -> Option<CycleBreakdown> {
+        self.cycle_breakdown
+    }
+
+    // Called whenever a push or pull wraps the stack pointer. Only exists
+    // in debug builds, release builds wrap silently.
+    #[cfg(debug_assertions)]
+    pub fn set_stack_wrap_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(StackWrap) + 'static,
+    {
+        self.stack_wrap_hook = Some(Box::new(hook));
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_stack_wrap(&mut self, wrap: StackWrap) {
+        if let Some(hook) = self.stack_wrap_hook.as_mut() {
+            hook(wrap);
+        }
+    }
+
+    // Effective address without side effects, used by tracing
+    pub fn get_non_immediate_addr(&self, mode: &AddressingMode, curr_addr: u16) -> u16 {
+        decode_addr(mode, curr_addr, self.register_x, self.register_y, &mut |addr| self.peek(addr))
+    }
+
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.program_counter,
-            _ => self.get_non_immediate_addr(mode, self.program_counter),
+            _ => {
+                // Operand and pointer fetches are real bus reads
+                let bus: &mut Bus = &mut self.bus;
+                let addr: u16 = decode_addr(mode, self.program_counter, self.register_x, self.register_y, &mut |addr| bus.mem_read(addr));
+                self.dummy_read(mode, addr);
+                addr
+            }
+        }
+    }
+
+    // Indexed modes add the index to the low byte first, so when the
+    // result crosses a page the un-carried address is read before the real one
+    fn dummy_read(&mut self, mode: &AddressingMode, addr: u16) {
+        let index: u8 = match mode {
+            AddressingMode::Absolute_X => self.register_x,
+            AddressingMode::Absolute_Y | AddressingMode::Indirect_Y => self.register_y,
+            _ => return,
+        };
+        let base: u16 = addr.wrapping_sub(index as u16);
+        if base & 0xFF00 != addr & 0xFF00 {
+            self.page_crossed = true;
+            self.mem_read((base & 0xFF00) | (addr & 0x00FF));
         }
     }
 
     fn push_stack(&mut self, val: u8) {
         self.mem_write(STACK_END | self.stack_ptr as u16, val);
+        #[cfg(debug_assertions)]
+        if self.stack_ptr == 0x00 {
+            self.check_stack_wrap(StackWrap::Overflow);
+        }
         self.set_stack_ptr(self.stack_ptr.wrapping_sub(1));
     }
 
@@ -132,6 +444,10 @@ impl CPU {
     }
 
     fn pull_stack(&mut self) -> u8 {
+        #[cfg(debug_assertions)]
+        if self.stack_ptr == 0xFF {
+            self.check_stack_wrap(StackWrap::Underflow);
+        }
         self.set_stack_ptr(self.stack_ptr.wrapping_add(1));
         self.mem_read(STACK_END | self.stack_ptr as u16)
     }
@@ -141,6 +457,33 @@ impl CPU {
         res | ((self.pull_stack() as u16) << 8)
     }
 
+    fn interrupt(&mut self, interrupt: &interrupt::Interrupt) {
+        self.enter_handler(interrupt);
+        if let Some(breakdown) = self.cycle_breakdown.as_mut() {
+            breakdown.interrupts += interrupt.cycles as usize;
+        }
+        self.bus.tick(interrupt.cycles);
+    }
+
+    fn enter_handler(&mut self, interrupt: &interrupt::Interrupt) {
+        // Save program counter and status, B flag depends on interrupt source
+        self.push_stack_u16(self.program_counter);
+        let mut flags: CPUFlags = self.status;
+        flags.set(CPUFlags::BRK, interrupt.brk_flag);
+        flags.insert(CPUFlags::BRK2);
+        self.push_stack(flags.bits);
+
+        // Block further interrupts and jump to handler
+        self.status.insert(CPUFlags::INT);
+        self.program_counter = self.mem_read_u16(interrupt.vector_addr);
+    }
+
+    // BRK is followed by a padding byte, so the handler returns past both
+    fn brk(&mut self) {
+        self.program_counter = self.program_counter.wrapping_add(1);
+        self.enter_handler(&interrupt::BRK);
+    }
+
     fn set_zero_and_neg_flags(&mut self, val: u8) {
         if val == 0 {
             self.status.insert(CPUFlags::ZERO);
@@ -211,6 +554,54 @@ impl CPU {
         self.set_acc(result);
     }
 
+    fn decimal_mode(&self) -> bool {
+        self.decimal_enabled && self.status.contains(CPUFlags::DEC)
+    }
+
+    // NMOS BCD addition, Z comes from the binary sum and N/V from the
+    // result before the high digit is adjusted
+    fn add_to_acc_decimal(&mut self, operand: u8) {
+        let carry: u16 = if self.status.contains(CPUFlags::CARRY) { 1 } else { 0 };
+        let binary: u8 = (self.accumulator as u16 + operand as u16 + carry) as u8;
+        let mut lo: u16 = (self.accumulator & 0x0F) as u16 + (operand & 0x0F) as u16 + carry;
+        let mut hi: u16 = (self.accumulator >> 4) as u16 + (operand >> 4) as u16;
+        if lo > 9 {
+            lo += 6;
+        }
+        if lo > 0x0F {
+            hi += 1;
+        }
+
+        let intermediate: u8 = ((hi << 4) | (lo & 0x0F)) as u8;
+        let overflow: bool = !(self.accumulator ^ operand) & (self.accumulator ^ intermediate) & 0x80 != 0;
+        self.status.set(CPUFlags::OVER, overflow);
+        self.status.set(CPUFlags::NEG, intermediate & 0x80 != 0);
+        self.status.set(CPUFlags::ZERO, binary == 0);
+
+        if hi > 9 {
+            hi += 6;
+        }
+        self.status.set(CPUFlags::CARRY, hi > 0x0F);
+        self.accumulator = ((hi << 4) | (lo & 0x0F)) as u8;
+    }
+
+    // NMOS BCD subtraction, flags match the binary subtraction
+    fn sub_from_acc_decimal(&mut self, operand: u8) {
+        let borrow: i16 = if self.status.contains(CPUFlags::CARRY) { 0 } else { 1 };
+        let mut lo: i16 = (self.accumulator & 0x0F) as i16 - (operand & 0x0F) as i16 - borrow;
+        let mut hi: i16 = (self.accumulator >> 4) as i16 - (operand >> 4) as i16;
+        if lo < 0 {
+            lo -= 6;
+            hi -= 1;
+        }
+        if hi < 0 {
+            hi -= 6;
+        }
+
+        self.add_to_acc(!operand);
+        self.accumulator = ((hi << 4) | (lo & 0x0F)) as u8;
+    }
+
     fn aac(&mut self) {
         // Get address and operand
         let addr: u16 = self.get_operand_address(&AddressingMode::Immediate);
@@ -228,7 +619,11 @@ impl CPU {
         // Add operand to accumulator
         let addr: u16 = self.get_operand_address(mode);
         let operand: u8 = self.mem_read(addr);
-        self.add_to_acc(operand);
+        if self.decimal_mode() {
+            self.add_to_acc_decimal(operand);
+        } else {
+            self.add_to_acc(operand);
+        }
     }
 
     fn and(&mut self, mode: &AddressingMode) {
@@ -301,10 +696,12 @@ impl CPU {
         self.lsr_acc();
     }
 
+    // Unstable on hardware, the result depends on analog effects that the
+    // magic constant approximates
     fn atx(&mut self) {
         let addr: u16 = self.get_operand_address(&AddressingMode::Immediate);
         let val: u8 = self.mem_read(addr);
-        self.set_acc(self.accumulator & val);
+        self.set_acc((self.accumulator | self.atx_magic) & val);
         self.set_reg_x(self.accumulator);
     }
 
@@ -342,12 +739,15 @@ impl CPU {
 
     fn branch(&mut self, condition: bool) {
         if !condition {
+            self.program_counter = self.program_counter.wrapping_add(1);
             return;
         }
         let operand: i8 = self.mem_read(self.program_counter) as i8;
-        self.program_counter = self.program_counter
-                                    .wrapping_add(1)
-                                    .wrapping_add(operand as u16);
+        let next: u16 = self.program_counter.wrapping_add(1);
+        self.program_counter = next.wrapping_add(operand as u16);
+
+        // Taken branches cost a cycle, and another if they land on a new page
+        self.branch_cycles = if next & 0xFF00 == self.program_counter & 0xFF00 { 1 } else { 2 };
     }
 
     fn cmp(&mut self, mode: &AddressingMode, register_val: u8) {
@@ -417,6 +817,15 @@ impl CPU {
         self.add_to_acc((operand as i8).wrapping_neg().wrapping_sub(1) as u8);
     }
 
+    // The pointer's high byte is read without carrying into the page, so
+    // ($30FF) takes it from $3000 and ($FFFF) from $FF00, never $0000
+    fn jmp_indirect(&mut self) {
+        let mem_addr: u16 = self.mem_read_u16(self.program_counter);
+        let lo: u8 = self.mem_read(mem_addr);
+        let hi: u8 = self.mem_read((mem_addr & 0xFF00) | (mem_addr.wrapping_add(1) & 0x00FF));
+        self.program_counter = (hi as u16) << 8 | (lo as u16);
+    }
+
     fn jsr(&mut self) {
         let addr: u16 = self.get_operand_address(&AddressingMode::Absolute);
         self.push_stack_u16(self.program_counter + 1);
@@ -614,6 +1023,10 @@ impl CPU {
     fn sbc(&mut self, mode: &AddressingMode) {
         let addr: u16 = self.get_operand_address(mode);
         let operand: u8 = self.mem_read(addr);
+        if self.decimal_mode() {
+            self.sub_from_acc_decimal(operand);
+            return;
+        }
         self.add_to_acc((operand as i8).wrapping_neg().wrapping_sub(1) as u8);
     }
 
@@ -661,14 +1074,26 @@ impl CPU {
 
     fn sxa(&mut self) {
         let addr: u16 = self.get_operand_address(&AddressingMode::Absolute_Y);
-        let high: u8 = (addr >> 8) as u8;
-        self.mem_write(addr, (high + 1) & self.register_x)
+        self.store_and_high(addr, self.register_y, self.register_x);
     }
 
     fn sya(&mut self) {
         let addr: u16 = self.get_operand_address(&AddressingMode::Absolute_X);
-        let high: u8 = (addr >> 8) as u8;
-        self.mem_write(addr, (high + 1) & self.register_y);
+        self.store_and_high(addr, self.register_x, self.register_y);
+    }
+
+    // SHX/SHY store the register ANDed with the base address high byte + 1.
+    // When indexing crosses a page that value also replaces the high byte of
+    // the address written to.
+    fn store_and_high(&mut self, addr: u16, index: u8, register: u8) {
+        let base: u16 = addr.wrapping_sub(index as u16);
+        let value: u8 = register & ((base >> 8) as u8).wrapping_add(1);
+        let target: u16 = if base & 0xFF00 != addr & 0xFF00 {
+            ((value as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(target, value);
     }
 
     fn xaa(&mut self) {
@@ -691,13 +1116,42 @@ impl CPU {
         self.run();
     }
 
+    // Assemble text source and run it like load_and_run, panicking on
+    // assembler errors
+    pub fn assemble_and_run(&mut self, source: &str) {
+        let program: Vec<u8> = asm::assemble(source, PRG_START).unwrap_or_else(|e| panic!("{}", e));
+        self.load_and_run(program);
+    }
+
+    // Same as load_and_run, but start at `pc` instead of the reset vector
+    pub fn load_and_run_at(&mut self, program: Vec<u8>, pc: u16) {
+        self.load(program);
+        self.set_start_pc(pc);
+        self.reset();
+        self.run();
+    }
+
+    // Make every reset jump to `pc` instead of the reset vector, e.g. 0xC000
+    // for nestest's automation mode
+    pub fn set_start_pc(&mut self, pc: u16) {
+        self.start_pc = Some(pc);
+    }
+
+    // Go back to starting at the reset vector
+    pub fn clear_start_pc(&mut self) {
+        self.start_pc = None;
+    }
+
     // Reset CPU values
     pub fn reset(&mut self) {
         self.accumulator = 0;
         self.register_x = 0;
         self.register_y = 0;
         self.status = CPUFlags::from_bits_truncate(0b0010_0100);
-        self.program_counter = self.mem_read_u16(PRG_REF);
+        self.program_counter = match self.start_pc {
+            Some(pc) => pc,
+            None => self.mem_read_u16(PRG_REF),
+        };
     }
 
     // Load program into memory
@@ -708,9 +1162,46 @@ impl CPU {
         self.mem_write_u16(PRG_REF, PRG_START);
     }
 
-    // Run program from memory
+    // Run program from memory, skipping the per-instruction callback
     pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+        while self.step() {}
+    }
+
+    // Step until the PC is `target` before an instruction runs, giving up
+    // after `max_instructions` or if the CPU halts. Returns true on reaching
+    // the target.
+    pub fn run_until_pc(&mut self, target: u16, max_instructions: usize) -> bool {
+        for _ in 0..max_instructions {
+            if self.program_counter == target {
+                return true;
+            }
+            if !self.step() {
+                return false;
+            }
+        }
+        self.program_counter == target
+    }
+
+    // Run a JSR and the whole subroutine, stopping once it returns to the
+    // instruction after the call. Anything else is a single step.
+    pub fn step_over(&mut self, max_instructions: usize) -> StepOver {
+        if self.peek(self.program_counter) != 0x20 {
+            return if self.step() { StepOver::Done } else { StepOver::Halted };
+        }
+        let return_addr: u16 = self.program_counter.wrapping_add(3);
+        for _ in 0..max_instructions {
+            if self.program_counter == return_addr {
+                return StepOver::Done;
+            }
+            if !self.step() {
+                return StepOver::Halted;
+            }
+        }
+        if self.program_counter == return_addr {
+            StepOver::Done
+        } else {
+            StepOver::OutOfBudget
+        }
     }
 
     pub fn load_snake(&mut self, program: Vec<u8>) {
@@ -724,131 +1215,203 @@ impl CPU {
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
         loop {
+            self.poll_interrupts();
             callback(self);
-            // Get current operation in program
-            let code: u8 = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state: u16 = self.program_counter;
-            let opcode: &&opcodes::OpCode = opcodes.get(&code).expect(&format!("OpCode {:x} is not recognized", code));
-
-            // Run corresponding operation function
-            match code {
-                0x0B | 0x2B => self.aac(),
-                0x87 | 0x97 | 0x83 | 0x8F => self.sax(&opcode.mode),
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
-                0x6B => self.arr(),
-                0x0A => self.asl_acc(),
-                0x06 | 0x16 | 0x0E | 0x1E => self.asl(&opcode.mode),
-                0x4B => self.asr(),
-                0xAB => self.atx(),
-                0x9F | 0x93 => self.axa(&opcode.mode),
-                0xCB => self.axs(),
-                0x90 => self.branch(!self.status.contains(CPUFlags::CARRY)),
-                0xB0 => self.branch(self.status.contains(CPUFlags::CARRY)),
-                0xF0 => self.branch(self.status.contains(CPUFlags::ZERO)),
-                0x24 | 0x2C => self.bit(&opcode.mode),
-                0x30 => self.branch(self.status.contains(CPUFlags::NEG)),
-                0xD0 => self.branch(!self.status.contains(CPUFlags::ZERO)),
-                0x10 => self.branch(!self.status.contains(CPUFlags::NEG)),
-                0x00 => {
-                    self.status.insert(CPUFlags::BRK);
-                    return;
-                },
-                0x50 => self.branch(!self.status.contains(CPUFlags::OVER)),
-                0x70 => self.branch(self.status.contains(CPUFlags::OVER)),
-                0x18 => self.status.remove(CPUFlags::CARRY),
-                0xD8 => self.status.remove(CPUFlags::DEC),
-                0x58 => self.status.remove(CPUFlags::INT),
-                0xB8 => self.status.remove(CPUFlags::OVER),
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.cmp(&opcode.mode, self.accumulator),
-                0xE0 | 0xE4 | 0xEC => self.cmp(&opcode.mode, self.register_x),
-                0xC0 | 0xC4 | 0xCC => self.cmp(&opcode.mode, self.register_y),
-                0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => self.dcp(&opcode.mode),
-                0xC6 | 0xD6 | 0xCE | 0xDE => self.dec(&opcode.mode),
-                0xCA => self.dex(),
-                0x88 => self.dey(),
-                0x04 | 0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xD4 | 0xE2 | 0xF4 => {},
-                0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => self.isc(&opcode.mode),
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
-                0xE6 | 0xF6 | 0xEE | 0xFE => self.inc(&opcode.mode),
-                0xE8 => self.inx(),
-                0xC8 => self.iny(),
-
-                // JMP
-                0x4C => {
-                    let jmp_addr: u16 = self.mem_read_u16(self.program_counter);
-                    self.program_counter = jmp_addr;
-                },
-
-                // JMP Indirect
-                0x6C => {
-                    let mem_addr: u16 = self.mem_read_u16(self.program_counter);
-                    let jmp_addr: u16 = if mem_addr & 0x00FF == 0x00FF {
-                        let lo: u8 = self.mem_read(mem_addr);
-                        let hi: u8 = self.mem_read(mem_addr & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_addr)
-                    };
-                    self.program_counter = jmp_addr;
-                }
-                0x20 => self.jsr(),
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => return,
-                0xBB => self.lar(),
-                0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => self.lax(&opcode.mode),
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.lda(&opcode.mode),
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode),
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode),
-                0x4A => self.lsr_acc(),
-                0x46 | 0x56 | 0x4E | 0x5E => self.lsr(&opcode.mode),
-                0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {},
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
-                0x48 => self.push_stack(self.accumulator),
-                0x08 => self.php(),
-                0x68 => self.pla(),
-                0x28 => self.plp(),
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => self.rla(&opcode.mode),
-                0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => self.rra(&opcode.mode),
-                0x2A => self.rol_acc(),
-                0x26 | 0x36 | 0x2E | 0x3E => {
-                    self.rol(&opcode.mode);
-                },
-                0x6A => self.ror_acc(),
-                0x66 | 0x76 | 0x6E | 0x7E => {
-                    self.ror(&opcode.mode);
-                },
-                0x40 => self.rti(),
-                0x60 => self.rts(),
-                0xEB | 0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(&opcode.mode),
-                0x38 => self.status.insert(CPUFlags::CARRY),
-                0xF8 => self.status.insert(CPUFlags::DEC),
-                0x78 => self.status.insert(CPUFlags::INT),
-                0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => self.slo(&opcode.mode),
-                0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => self.sre(&opcode.mode),
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
-                0x86 | 0x96 | 0x8E => self.stx(&opcode.mode),
-                0x84 | 0x94 | 0x8C => self.sty(&opcode.mode),
-                0x9E => self.sxa(),
-                0x9C => self.sya(),
-                0xAA => self.set_reg_x(self.accumulator),
-                0xA8 => self.set_reg_y(self.accumulator),
-                0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {},
-                0xBA => self.set_reg_x(self.stack_ptr),
-                0x8A => self.set_acc(self.register_x),
-                0x9A => self.set_stack_ptr(self.register_x),
-                0x98 => self.set_acc(self.register_y),
-                0x8B => self.xaa(),
-                0x9B => self.xas(),
+            if self.check_legal().is_err() || !self.execute_instruction() {
+                return;
             }
+        }
+    }
+
+    // Run a single instruction, servicing pending interrupts first.
+    // Returns false once the program halts.
+    pub fn step(&mut self) -> bool {
+        self.try_step().unwrap_or(false)
+    }
+
+    // Like step, but reports why the CPU stopped. An illegal opcode that
+    // isn't allowed is left unexecuted with the PC pointing at it.
+    pub fn try_step(&mut self) -> Result<bool, StepError> {
+        self.poll_interrupts();
+        self.check_legal()?;
+        Ok(self.execute_instruction())
+    }
+
+    fn check_legal(&self) -> Result<(), StepError> {
+        if self.allow_illegal {
+            return Ok(());
+        }
+        let code: u8 = self.peek(self.program_counter);
+        match opcodes::OPCODE_TABLE[code as usize] {
+            Some(opcode) if opcode.is_illegal() => Err(StepError::IllegalOpcode(code)),
+            _ => Ok(()),
+        }
+    }
+
+    fn poll_interrupts(&mut self) {
+        // NMI can't be masked and wins over IRQ
+        if self.bus.poll_nmi() {
+            self.interrupt(&interrupt::NMI);
+            return;
+        }
+        // Service IRQ while any device holds the line and it isn't masked
+        if self.bus.irq_pending() && !self.status.contains(CPUFlags::INT) {
+            self.interrupt(&interrupt::IRQ);
+        }
+    }
+
+    fn execute_instruction(&mut self) -> bool {
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.insert(self.program_counter);
+        }
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+        // Get current operation in program
+        let code: u8 = self.mem_read(self.program_counter);
+        if let Some(history) = self.history.as_mut() {
+            if history.len() == HISTORY_LEN {
+                history.pop_front();
             }
+            history.push_back((self.program_counter, code));
+        }
+        self.program_counter += 1;
+        self.page_crossed = false;
+        self.branch_cycles = 0;
+        let program_counter_state: u16 = self.program_counter;
+        // Table entries are 'static, so holding one doesn't borrow self
+        // while the operation below mutates the CPU and bus
+        let opcode: &'static opcodes::OpCode = opcodes::OPCODE_TABLE[code as usize]
+            .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+
+        // Run corresponding operation function
+        match code {
+            0x0B | 0x2B => self.aac(),
+            0x87 | 0x97 | 0x83 | 0x8F => self.sax(&opcode.mode),
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
+            0x6B => self.arr(),
+            0x0A => self.asl_acc(),
+            0x06 | 0x16 | 0x0E | 0x1E => self.asl(&opcode.mode),
+            0x4B => self.asr(),
+            0xAB => self.atx(),
+            0x9F | 0x93 => self.axa(&opcode.mode),
+            0xCB => self.axs(),
+            0x90 => self.branch(!self.status.contains(CPUFlags::CARRY)),
+            0xB0 => self.branch(self.status.contains(CPUFlags::CARRY)),
+            0xF0 => self.branch(self.status.contains(CPUFlags::ZERO)),
+            0x24 | 0x2C => self.bit(&opcode.mode),
+            0x30 => self.branch(self.status.contains(CPUFlags::NEG)),
+            0xD0 => self.branch(!self.status.contains(CPUFlags::ZERO)),
+            0x10 => self.branch(!self.status.contains(CPUFlags::NEG)),
+            0x00 if self.brk_halts => {
+                self.status.insert(CPUFlags::BRK);
+                return false;
+            },
+            0x00 => self.brk(),
+            0x50 => self.branch(!self.status.contains(CPUFlags::OVER)),
+            0x70 => self.branch(self.status.contains(CPUFlags::OVER)),
+            0x18 => self.status.remove(CPUFlags::CARRY),
+            0xD8 => self.status.remove(CPUFlags::DEC),
+            0x58 => self.status.remove(CPUFlags::INT),
+            0xB8 => self.status.remove(CPUFlags::OVER),
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.cmp(&opcode.mode, self.accumulator),
+            0xE0 | 0xE4 | 0xEC => self.cmp(&opcode.mode, self.register_x),
+            0xC0 | 0xC4 | 0xCC => self.cmp(&opcode.mode, self.register_y),
+            0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => self.dcp(&opcode.mode),
+            0xC6 | 0xD6 | 0xCE | 0xDE => self.dec(&opcode.mode),
+            0xCA => self.dex(),
+            0x88 => self.dey(),
+            0x04 | 0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xD4 | 0xE2 | 0xF4 => {},
+            0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => self.isc(&opcode.mode),
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
+            0xE6 | 0xF6 | 0xEE | 0xFE => self.inc(&opcode.mode),
+            0xE8 => self.inx(),
+            0xC8 => self.iny(),
+
+            // JMP
+            0x4C => {
+                let jmp_addr: u16 = self.mem_read_u16(self.program_counter);
+                self.program_counter = jmp_addr;
+            },
+
+            // JMP Indirect
+            0x6C => self.jmp_indirect(),
+            0x20 => self.jsr(),
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => return false,
+            0xBB => self.lar(),
+            0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => self.lax(&opcode.mode),
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.lda(&opcode.mode),
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode),
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode),
+            0x4A => self.lsr_acc(),
+            0x46 | 0x56 | 0x4E | 0x5E => self.lsr(&opcode.mode),
+            0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {},
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
+            0x48 => self.push_stack(self.accumulator),
+            0x08 => self.php(),
+            0x68 => self.pla(),
+            0x28 => self.plp(),
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => self.rla(&opcode.mode),
+            0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => self.rra(&opcode.mode),
+            0x2A => self.rol_acc(),
+            0x26 | 0x36 | 0x2E | 0x3E => {
+                self.rol(&opcode.mode);
+            },
+            0x6A => self.ror_acc(),
+            0x66 | 0x76 | 0x6E | 0x7E => {
+                self.ror(&opcode.mode);
+            },
+            0x40 => self.rti(),
+            0x60 => self.rts(),
+            0xEB | 0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(&opcode.mode),
+            0x38 => self.status.insert(CPUFlags::CARRY),
+            0xF8 => self.status.insert(CPUFlags::DEC),
+            0x78 => self.status.insert(CPUFlags::INT),
+            0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => self.slo(&opcode.mode),
+            0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => self.sre(&opcode.mode),
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
+            0x86 | 0x96 | 0x8E => self.stx(&opcode.mode),
+            0x84 | 0x94 | 0x8C => self.sty(&opcode.mode),
+            0x9E => self.sxa(),
+            0x9C => self.sya(),
+            0xAA => self.set_reg_x(self.accumulator),
+            0xA8 => self.set_reg_y(self.accumulator),
+            0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
+                // Absolute NOPs still read their operand
+                let addr: u16 = self.get_operand_address(&opcode.mode);
+                self.mem_read(addr);
+            },
+            0xBA => self.set_reg_x(self.stack_ptr),
+            0x8A => self.set_acc(self.register_x),
+            0x9A => self.set_stack_ptr(self.register_x),
+            0x98 => self.set_acc(self.register_y),
+            0x8B => self.xaa(),
+            0x9B => self.xas(),
+        }
+
+        // Jumps, returns and branches set the PC themselves, even when the
+        // target is the byte right after the opcode
+        let sets_pc: bool = matches!(
+            code,
+            0x00 | 0x4C | 0x6C | 0x20 | 0x40 | 0x60 | 0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xB0 | 0xD0 | 0xF0
+        );
+        if !sets_pc && program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+
+        let mut cycles: u8 = opcode.cycles + self.branch_cycles;
+        if opcode.page_cross_penalty && self.page_crossed {
+            cycles += 1;
+        }
+        if let Some(breakdown) = self.cycle_breakdown.as_mut() {
+            breakdown.add(opcode, cycles);
+        }
+        self.bus.tick(cycles);
+        let stall: usize = self.bus.run_dma();
+        if let Some(breakdown) = self.cycle_breakdown.as_mut() {
+            breakdown.dma += stall;
         }
+        true
     }
 }
 
@@ -856,7 +1419,13 @@ impl CPU {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::bus::{BusAccess, IrqSource};
+    use crate::mapper::test::mmc1_write;
+    use crate::ppu::{StatusRegister, PPU};
+    use crate::rom::Rom;
     use crate::rom::test;
+    use std::cell::RefCell;
+    use std::rc::Rc;
     use test_case::test_case;
 
     #[test_case(
@@ -973,6 +1542,27 @@ mod test {
         assert_eq!(res, 0x05);
     }
 
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_stack_wrap_hook() {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        let wraps: Rc<RefCell<Vec<StackWrap>>> = Rc::new(RefCell::new(Vec::new()));
+        let seen: Rc<RefCell<Vec<StackWrap>>> = wraps.clone();
+        cpu.set_stack_wrap_hook(move |wrap| seen.borrow_mut().push(wrap));
+
+        cpu.stack_ptr = 0x02;
+        cpu.push_stack(0x05);
+        cpu.push_stack(0x06);
+        assert!(wraps.borrow().is_empty());
+        cpu.push_stack(0x07);
+        assert_eq!(cpu.stack_ptr, 0xFF);
+        assert_eq!(*wraps.borrow(), vec![StackWrap::Overflow]);
+
+        cpu.pull_stack();
+        assert_eq!(*wraps.borrow(), vec![StackWrap::Overflow, StackWrap::Underflow]);
+    }
+
     #[test]
     fn test_pull_u16() {
         let bus: Bus = Bus::new(test::test_rom());
@@ -1002,6 +1592,42 @@ mod test {
         assert_eq!(cpu.program_counter, PRG_START);
     }
 
+    fn mmc1_rom() -> Rom {
+        // Each 16KB bank has its own reset vector, 0x9n00 for bank n
+        let mut prg: Vec<u8> = vec![0; 4 * 0x4000];
+        for bank in 0..4 {
+            prg[bank * 0x4000 + 0x3FFD] = 0x90 + bank as u8;
+        }
+        let raw: Vec<u8> = test::create_rom(test::TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x04, 0x01, 0x10, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: prg,
+            chr_rom: vec![0; 0x2000],
+        });
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_reset_vector_from_fixed_last_bank() {
+        let mut cpu: CPU = CPU::new(Bus::new(mmc1_rom()));
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x9300);
+    }
+
+    #[test]
+    fn test_reset_vector_from_switched_bank() {
+        let mut cpu: CPU = CPU::new(Bus::new(mmc1_rom()));
+
+        // Fix first bank at 0x8000, switch 0xC000 to bank 2
+        mmc1_write(&mut |a, d| cpu.mem_write(a, d), 0x8000, 0x08);
+        mmc1_write(&mut |a, d| cpu.mem_write(a, d), 0xE000, 2);
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x9200);
+        assert_eq!(cpu.mem_read_u16(0xFFFC), 0x9200);
+    }
+
     #[test]
     fn test_load() {
         let bus: Bus = Bus::new(test::test_rom());
@@ -1026,6 +1652,443 @@ mod test {
         assert_eq!(cpu.program_counter, PRG_START + 3);
     }
 
+    #[test]
+    fn test_coverage() {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        // LDX #$02, DEX, BNE -3, JMP $060A, LDA #$FF, BRK
+        let program: Vec<u8> = vec![
+            0xA2, 0x02, 0xCA, 0xD0, 0xFD, 0x4C, 0x0A, 0x06, 0xA9, 0xFF, 0x00,
+        ];
+        load_bytes(&mut cpu, &program);
+        assert!(cpu.covered().is_none());
+
+        cpu.enable_coverage();
+        cpu.run();
+        let expected: HashSet<u16> = [0x0600, 0x0602, 0x0603, 0x0605, 0x060A].into_iter().collect();
+        assert_eq!(cpu.covered(), Some(&expected));
+    }
+
+    #[test]
+    fn test_assemble_and_run() {
+        let mut cpu: CPU = CPU::new(Bus::flat());
+        cpu.assemble_and_run("LDA #$05\nTAX\nBRK");
+        assert_eq!(cpu.register_x, 0x05);
+    }
+
+    #[test_case("SED\nCLC\nLDA #$09\nADC #$01\nBRK", false, 0x0A, CPUFlags::empty(); "nes adc")]
+    #[test_case("SED\nCLC\nLDA #$09\nADC #$01\nBRK", true, 0x10, CPUFlags::empty(); "bcd adc")]
+    #[test_case("SED\nSEC\nLDA #$58\nADC #$46\nBRK", true, 0x05, CPUFlags::CARRY | CPUFlags::OVER | CPUFlags::NEG; "bcd adc carry out")]
+    #[test_case("SED\nSEC\nLDA #$10\nSBC #$01\nBRK", false, 0x0F, CPUFlags::CARRY; "nes sbc")]
+    #[test_case("SED\nSEC\nLDA #$10\nSBC #$01\nBRK", true, 0x09, CPUFlags::CARRY; "bcd sbc")]
+    #[test_case("SED\nSEC\nLDA #$12\nSBC #$21\nBRK", true, 0x91, CPUFlags::NEG; "bcd sbc borrow")]
+    fn test_decimal_mode(source: &str, decimal_enabled: bool, expected: u8, expected_flags: CPUFlags) {
+        let mut cpu: CPU = CPU::new(Bus::flat());
+        cpu.decimal_enabled = decimal_enabled;
+        cpu.assemble_and_run(source);
+        assert_eq!(cpu.accumulator, expected);
+        let flags: CPUFlags = CPUFlags::CARRY | CPUFlags::ZERO | CPUFlags::OVER | CPUFlags::NEG;
+        assert_eq!(cpu.status & flags, expected_flags);
+    }
+
+    fn load_source(cpu: &mut CPU, source: &str) {
+        let program: Vec<u8> = asm::assemble(source, 0x0600).unwrap();
+        load_bytes(cpu, &program);
+    }
+
+    // Write machine code at 0x0600 and point the PC at it
+    fn load_bytes(cpu: &mut CPU, program: &[u8]) {
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mem_write(0x0600 + i as u16, *byte);
+        }
+        cpu.program_counter = 0x0600;
+    }
+
+    #[test]
+    fn test_illegal_opcodes_disallowed() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        // SLO $10
+        cpu.mem_write(0x0600, 0x07);
+        cpu.mem_write(0x0601, 0x10);
+        cpu.mem_write(0x10, 0x41);
+        cpu.program_counter = 0x0600;
+        cpu.allow_illegal = false;
+
+        assert_eq!(cpu.try_step(), Err(StepError::IllegalOpcode(0x07)));
+        assert!(!cpu.step());
+        assert_eq!(cpu.program_counter, 0x0600);
+        assert_eq!(cpu.mem_read(0x10), 0x41);
+
+        cpu.allow_illegal = true;
+        assert_eq!(cpu.try_step(), Ok(true));
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert_eq!(cpu.mem_read(0x10), 0x82);
+        assert_eq!(cpu.accumulator, 0x82);
+    }
+
+    #[test_case(100, true, 0x0605, 0x00; "hits target")]
+    #[test_case(4, false, 0x0603, 0x01; "budget exhausted")]
+    #[test_case(0, false, 0x0600, 0x00; "no budget")]
+    fn test_run_until_pc(max_instructions: usize, expected: bool, expected_pc: u16, expected_x: u8) {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "LDX #$03\nloop: DEX\nBNE loop\nLDA #$01\nBRK");
+
+        assert_eq!(cpu.run_until_pc(0x0605, max_instructions), expected);
+        assert_eq!(cpu.program_counter, expected_pc);
+        assert_eq!(cpu.register_x, expected_x);
+        assert_eq!(cpu.accumulator, 0x00);
+    }
+
+    #[test]
+    fn test_run_until_pc_stops_on_halt() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "NOP\nBRK");
+        assert!(!cpu.run_until_pc(0x0700, 100));
+    }
+
+    #[test_case(0xFF; "all flags")]
+    #[test_case(0x00; "no flags")]
+    #[test_case(0xC3; "mixed flags")]
+    fn test_php_plp_break_flag(flags: u8) {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        // PHP, PLP
+        cpu.mem_write(0x0600, 0x08);
+        cpu.mem_write(0x0601, 0x28);
+        cpu.program_counter = 0x0600;
+        cpu.status = CPUFlags::from_bits_truncate(flags);
+
+        // The pushed copy always has both B bits set
+        cpu.step();
+        let pushed: u8 = cpu.mem_read(0x0100 + cpu.stack_ptr as u16 + 1);
+        assert_eq!(pushed, flags | 0b0011_0000);
+
+        // Pulling restores every other flag, but B never reaches the live status
+        cpu.status = CPUFlags::from_bits_truncate(!flags);
+        cpu.step();
+        assert_eq!(cpu.status.bits(), (flags & !0b0001_0000) | 0b0010_0000);
+        assert!(!cpu.status.contains(CPUFlags::BRK));
+    }
+
+    #[test]
+    fn test_nmi_serviced_before_next_instruction() {
+        let mut cpu: CPU = CPU::new(Bus::flat());
+        cpu.mem_write_u16(0xFFFA, 0x0700);
+        // NOP in the main program, LDX #$09 in the handler
+        cpu.mem_write(0x0600, 0xEA);
+        cpu.mem_write(0x0700, 0xA2);
+        cpu.mem_write(0x0701, 0x09);
+        cpu.program_counter = 0x0600;
+        cpu.status.insert(CPUFlags::INT);
+
+        let ppu: &mut PPU = cpu.bus.ppu_mut();
+        ppu.skip_warmup();
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.write_register(0x2000, 0x80);
+        cpu.step();
+
+        assert_eq!(cpu.register_x, 0x09);
+        assert_eq!(cpu.program_counter, 0x0702);
+        assert_eq!(cpu.mem_read_u16(0x01FC), 0x0600);
+    }
+
+    #[test]
+    fn test_brk_vectors_and_returns_past_padding() {
+        let mut cpu: CPU = CPU::new(Bus::flat());
+        cpu.brk_halts = false;
+        cpu.mem_write_u16(0xFFFE, 0x0700);
+        // LDX #$01, BRK, padding, INX with the handler LDA #$42, RTI
+        load_bytes(&mut cpu, &[0xA2, 0x01, 0x00, 0xFF, 0xE8]);
+        cpu.mem_write(0x0700, 0xA9);
+        cpu.mem_write(0x0701, 0x42);
+        cpu.mem_write(0x0702, 0x40);
+
+        cpu.step();
+        let cycles: usize = cpu.bus.cycles();
+        assert!(cpu.step());
+        assert_eq!(cpu.bus.cycles() - cycles, 7);
+        assert_eq!(cpu.program_counter, 0x0700);
+        assert_eq!(cpu.mem_read_u16(0x01FC), 0x0604);
+        assert_eq!(cpu.mem_read(0x01FB) & 0b0011_0000, 0b0011_0000);
+        assert!(cpu.status.contains(CPUFlags::INT));
+
+        assert!(cpu.run_until_pc(0x0605, 4));
+        assert_eq!(cpu.accumulator, 0x42);
+        assert_eq!(cpu.register_x, 0x02);
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "JSR sub\nLDA #$02\nBRK\nsub: LDX #$05\nINX\nRTS");
+
+        assert_eq!(cpu.step_over(100), StepOver::Done);
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 0x06);
+        assert_eq!(cpu.accumulator, 0x00);
+
+        assert_eq!(cpu.step_over(100), StepOver::Done);
+        assert_eq!(cpu.program_counter, 0x0605);
+        assert_eq!(cpu.accumulator, 0x02);
+    }
+
+    #[test]
+    fn test_step_over_single_instruction() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "LDA #$07\nBRK");
+
+        // Not a JSR, so the budget doesn't matter
+        assert_eq!(cpu.step_over(0), StepOver::Done);
+        assert_eq!(cpu.program_counter, 0x0602);
+        assert_eq!(cpu.accumulator, 0x07);
+        assert_eq!(cpu.step_over(0), StepOver::Halted);
+    }
+
+    #[test]
+    fn test_step_over_halts_in_subroutine() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "JSR sub\nNOP\nsub: BRK");
+        assert_eq!(cpu.step_over(100), StepOver::Halted);
+    }
+
+    #[test]
+    fn test_cycle_breakdown() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "LDX #$03\nloop: STA $10,X\nINC $20\nASL A\nDEX\nBNE loop\nJSR sub\nBRK\nsub: LDA $20\nRTS");
+        assert_eq!(cpu.cycle_breakdown(), None);
+        cpu.enable_cycle_breakdown();
+
+        let before: usize = cpu.bus.cycles();
+        cpu.interrupt(&interrupt::IRQ);
+        cpu.program_counter = 0x0600;
+        while cpu.step() {}
+
+        let breakdown: CycleBreakdown = cpu.cycle_breakdown().unwrap();
+        assert_eq!(breakdown, CycleBreakdown { reads: 17, writes: 27, branches: 20, interrupts: 7, dma: 0 });
+        assert_eq!(breakdown.total(), cpu.bus.cycles() - before);
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "LDA #$07\nSTA $0203\nLDA #$02\nSTA $4014\nBRK");
+        cpu.enable_cycle_breakdown();
+        while cpu.step() {}
+
+        let breakdown: CycleBreakdown = cpu.cycle_breakdown().unwrap();
+        assert_eq!(breakdown.dma, 513);
+        assert_eq!(breakdown.total(), cpu.bus.cycles());
+        assert_eq!(cpu.bus.ppu().oam[3], 0x07);
+    }
+
+    #[test]
+    fn test_step_over_budget() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "JSR sub\nBRK\nsub: NOP\nNOP\nRTS");
+        assert_eq!(cpu.step_over(2), StepOver::OutOfBudget);
+        assert_eq!(cpu.program_counter, 0x0605);
+    }
+
+    #[test]
+    fn test_start_pc_override() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x0101);
+
+        cpu.set_start_pc(0xC000);
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0xC000);
+    }
+
+    #[test]
+    fn test_load_and_run_at() {
+        let mut cpu: CPU = CPU::new(Bus::flat());
+        // LDX #$07, then the start point LDA #$05, BRK
+        cpu.load_and_run_at(vec![0xA2, 0x07, 0xA9, 0x05, 0x00], 0x8002);
+        assert_eq!(cpu.accumulator, 0x05);
+        assert_eq!(cpu.register_x, 0x00);
+    }
+
+    #[test_case(&[0xB5, 0x10], 0x05, 0x00, Some(0x0015); "zero page x")]
+    #[test_case(&[0xB5, 0x10], 0xF5, 0x00, Some(0x0005); "zero page x wraps")]
+    #[test_case(&[0xBD, 0xF0, 0x02], 0x20, 0x00, Some(0x0310); "absolute x")]
+    #[test_case(&[0xB1, 0x40], 0x00, 0x04, Some(0x1238); "indirect y")]
+    #[test_case(&[0xA9, 0x10], 0x00, 0x00, None; "immediate")]
+    #[test_case(&[0x0A], 0x00, 0x00, None; "accumulator")]
+    #[test_case(&[0xE8], 0x00, 0x00, None; "implied")]
+    fn test_current_effective_address(program: &[u8], x: u8, y: u8, expected: Option<u16>) {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_bytes(&mut cpu, program);
+        cpu.mem_write_u16(0x40, 0x1234);
+        cpu.register_x = x;
+        cpu.register_y = y;
+        let cycles: usize = cpu.bus.cycles();
+
+        assert_eq!(cpu.current_effective_address(), expected);
+        assert_eq!(cpu.bus.cycles(), cycles);
+    }
+
+    #[test_case(&[0x4C, 0x34, 0x12], [0x4C, 0x34, 0x12], 3; "jmp absolute")]
+    #[test_case(&[0xA9, 0x42, 0xFF], [0xA9, 0x42, 0x00], 2; "immediate")]
+    #[test_case(&[0xE8, 0xFF, 0xFF], [0xE8, 0x00, 0x00], 1; "implied")]
+    fn test_instruction_bytes(program: &[u8], expected: [u8; 3], expected_len: u8) {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_bytes(&mut cpu, program);
+        assert_eq!(cpu.instruction_bytes(0x0600), (expected, expected_len));
+    }
+
+    #[test]
+    fn test_peek_instruction() {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        cpu.mem_write(0x0600, 0xA9);
+        cpu.mem_write(0x0601, 0x3C);
+        cpu.program_counter = 0x0600;
+        let cycles: usize = cpu.bus.cycles();
+
+        assert_eq!(cpu.peek_instruction(), (0x0600, String::from("LDA #$3C")));
+        assert_eq!(cpu.program_counter, 0x0600);
+        assert_eq!(cpu.bus.cycles(), cycles);
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "LDA #$80\nLDX #$12\nLDY #$34\nPHA\nSEC\nSED");
+        for _ in 0..6 {
+            cpu.step();
+        }
+        let state: [u8; CPU_STATE_LEN] = cpu.serialize();
+        assert_eq!(state[..7], [0x80, 0x12, 0x34, 0xFC, 0b0010_1101, 0x09, 0x06]);
+        assert_eq!(state[7..], (cpu.bus.cycles() as u64).to_le_bytes());
+
+        let mut restored: CPU = CPU::new(Bus::new(test::test_rom()));
+        restored.deserialize(&state).unwrap();
+        assert_eq!(restored.serialize(), state);
+        assert_eq!(restored.program_counter, 0x0609);
+        assert_eq!(restored.bus.cycles(), cpu.bus.cycles());
+        assert!(restored.deserialize(&state[1..]).is_err());
+    }
+
+    #[test]
+    fn test_disasm_window() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "LDA #$01\nSTA $0200\nINX\nJMP $0600\nLDY $10");
+        let cycles: usize = cpu.bus.cycles();
+
+        let expected: Vec<(u16, String)> = vec![
+            (0x0600, String::from("LDA #$01")),
+            (0x0602, String::from("STA $0200")),
+            (0x0605, String::from("INX")),
+            (0x0606, String::from("JMP $0600")),
+        ];
+        assert_eq!(cpu.disasm_window(0x0600, 0, 4), expected);
+        assert_eq!(cpu.disasm_window(0x0606, 3, 2)[..4], expected[..]);
+        assert_eq!(cpu.disasm_window(0x0606, 3, 2)[4], (0x0609, String::from("LDY $10")));
+        assert_eq!(cpu.disasm_window(0x0606, 1, 1), expected[2..4].to_vec());
+        assert_eq!(cpu.bus.cycles(), cycles);
+    }
+
+    #[test]
+    fn test_history() {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        // LDX #$02, DEX, BNE -3, BRK
+        let program: Vec<u8> = vec![0xA2, 0x02, 0xCA, 0xD0, 0xFD, 0x00];
+        load_bytes(&mut cpu, &program);
+        cpu.enable_history();
+        cpu.run();
+
+        let expected: Vec<(u16, u8)> = vec![
+            (0x0605, 0x00),
+            (0x0603, 0xD0),
+            (0x0602, 0xCA),
+            (0x0603, 0xD0),
+            (0x0602, 0xCA),
+            (0x0600, 0xA2),
+        ];
+        assert_eq!(cpu.history(), expected);
+    }
+
+    #[test]
+    fn test_history_keeps_newest() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_countdown_loop(&mut cpu);
+        assert!(cpu.history().is_empty());
+        cpu.enable_history();
+        cpu.run();
+
+        let history: Vec<(u16, u8)> = cpu.history();
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history[0], (0x060A, 0x00));
+        assert_eq!(history[1], (0x0608, 0xD0));
+    }
+
+    fn load_countdown_loop(cpu: &mut CPU) {
+        // Nested DEX/DEY countdown loops, then BRK
+        let program: Vec<u8> = vec![
+            0xA0, 0x00, 0xA2, 0x00, 0xCA, 0xD0, 0xFD, 0x88, 0xD0, 0xF8, 0x00,
+        ];
+        load_bytes(cpu, &program);
+    }
+
+    #[test]
+    fn test_run_matches_run_with_callback() {
+        let mut fast: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_countdown_loop(&mut fast);
+        fast.run();
+
+        let mut slow: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_countdown_loop(&mut slow);
+        let mut steps: usize = 0;
+        slow.run_with_callback(|_| steps += 1);
+
+        assert_eq!(fast.program_counter, slow.program_counter);
+        assert_eq!(fast.status, slow.status);
+        assert_eq!(fast.bus.cycles(), slow.bus.cycles());
+        assert!(steps > 0x10000);
+    }
+
+    #[test]
+    fn test_run_with_callback_reading_memory() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        load_source(&mut cpu, "LDX #$03\nloop: STX $10\nDEX\nBNE loop\nBRK");
+        cpu.mem_write(0x10, 0xFF);
+
+        let mut seen: Vec<(u16, u8)> = Vec::new();
+        cpu.run_with_callback(|cpu| {
+            let opcode: u8 = cpu.mem_read(cpu.program_counter);
+            if opcode == 0xCA {
+                seen.push((cpu.program_counter, cpu.mem_read(0x10)));
+            }
+        });
+
+        assert_eq!(seen, vec![(0x0604, 0x03), (0x0604, 0x02), (0x0604, 0x01)]);
+        assert_eq!(cpu.register_x, 0x00);
+        assert_eq!(cpu.program_counter, 0x0608);
+    }
+
+    // Workload for comparing run against run_with_callback under a
+    // profiler, run with `cargo test --release -- --ignored`
+    #[test]
+    #[ignore]
+    fn bench_run_skips_callback() {
+        use std::hint::black_box;
+
+        for _ in 0..20 {
+            let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+            load_countdown_loop(&mut cpu);
+            cpu.run();
+            let fast_cycles: usize = cpu.bus.cycles();
+
+            let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+            load_countdown_loop(&mut cpu);
+            cpu.run_with_callback(|cpu| {
+                black_box(cpu.program_counter);
+            });
+            assert_eq!(cpu.bus.cycles(), fast_cycles);
+        }
+    }
+
     #[test]
     fn test_load_and_run() {
         let bus: Bus = Bus::new(test::test_rom());
@@ -1107,6 +2170,7 @@ mod test {
         cpu.accumulator = accumulator;
         cpu.mem_write(0x00, mem);
         cpu.status = initial_status;
+        cpu.program_counter = 0x00;
         cpu.adc(&AddressingMode::Immediate);
         assert_eq!(cpu.accumulator, expected_acc);
         assert_eq!(cpu.status, expected_status);
@@ -1208,6 +2272,205 @@ mod test {
         assert_eq!(cpu.status, CPUFlags::from_bits_truncate(0b0010_0100) | CPUFlags::BRK);
     }
 
+    // Count reads of PPUSTATUS while running `program` from 0x0600
+    fn count_status_reads(program: &[u8], register_x: u8, register_y: u8) -> usize {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        let reads: Rc<RefCell<usize>> = Rc::new(RefCell::new(0));
+        let counter: Rc<RefCell<usize>> = reads.clone();
+        cpu.bus.set_read_watch(0x2002, move |_| *counter.borrow_mut() += 1);
+
+        cpu.mem_write_u16(0x0010, 0x20F0);
+        load_bytes(&mut cpu, program);
+        cpu.register_x = register_x;
+        cpu.register_y = register_y;
+        cpu.run();
+        let count: usize = *reads.borrow();
+        count
+    }
+
+    #[test_case(&[0xBD, 0xF0, 0x20, 0x00], 0x12, 0x00, 1; "Absolute X crossing")]
+    #[test_case(&[0xBD, 0xF0, 0x20, 0x00], 0x02, 0x00, 0; "Absolute X same page")]
+    #[test_case(&[0xB9, 0xF0, 0x20, 0x00], 0x00, 0x12, 1; "Absolute Y crossing")]
+    #[test_case(&[0xB1, 0x10, 0x00], 0x00, 0x12, 1; "Indirect Y crossing")]
+    #[test_case(&[0xB1, 0x10, 0x00], 0x00, 0x02, 0; "Indirect Y same page")]
+    fn test_page_cross_dummy_read(program: &[u8], register_x: u8, register_y: u8, expected: usize) {
+        assert_eq!(count_status_reads(program, register_x, register_y), expected);
+    }
+
+    #[test_case(0x0680, &[0xBD, 0x80, 0x02], 0x10, 4; "lda absolute x same page")]
+    #[test_case(0x0680, &[0xBD, 0xF8, 0x02], 0x10, 5; "lda absolute x crossing")]
+    #[test_case(0x0680, &[0xB9, 0xF8, 0x02], 0x10, 5; "lda absolute y crossing")]
+    #[test_case(0x0680, &[0xB1, 0x20], 0x01, 5; "lda indirect y same page")]
+    #[test_case(0x0680, &[0xB1, 0x20], 0x10, 6; "lda indirect y crossing")]
+    #[test_case(0x0680, &[0x9D, 0x80, 0x02], 0x10, 5; "sta absolute x same page")]
+    #[test_case(0x0680, &[0x9D, 0xF8, 0x02], 0x10, 5; "sta absolute x crossing")]
+    #[test_case(0x0680, &[0x1E, 0xF8, 0x02], 0x10, 7; "asl absolute x crossing")]
+    #[test_case(0x0680, &[0xDC, 0xF8, 0x02], 0x10, 5; "nop absolute x crossing")]
+    #[test_case(0x0680, &[0xD0, 0x10], 0x00, 2; "branch not taken")]
+    #[test_case(0x0680, &[0xF0, 0x10], 0x00, 3; "branch taken")]
+    #[test_case(0x0680, &[0xF0, 0x7F], 0x00, 4; "branch taken crossing")]
+    #[test_case(0x0680, &[0xF0, 0x80], 0x00, 3; "branch taken backwards")]
+    #[test_case(0x0610, &[0xF0, 0x80], 0x00, 4; "branch taken backwards crossing")]
+    fn test_extra_cycles(origin: u16, program: &[u8], index: u8, expected: usize) {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mem_write(origin + i as u16, *byte);
+        }
+        cpu.mem_write_u16(0x0020, 0x02F8);
+        cpu.program_counter = origin;
+        cpu.register_x = index;
+        cpu.register_y = index;
+        cpu.status.insert(CPUFlags::ZERO);
+
+        let before: usize = cpu.bus.cycles();
+        cpu.step();
+        assert_eq!(cpu.bus.cycles() - before, expected);
+    }
+
+    #[test_case(0x9E, 0x01, 0x0211, 0x0211, 0x01; "shx same page")]
+    #[test_case(0x9E, 0x20, 0x0310, 0x0110, 0x01; "shx page cross")]
+    #[test_case(0x9C, 0x01, 0x0211, 0x0211, 0x01; "shy same page")]
+    #[test_case(0x9C, 0x20, 0x0310, 0x0110, 0x01; "shy page cross")]
+    fn test_shx_shy_high_byte(code: u8, index: u8, indexed: u16, target: u16, value: u8) {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        let base: u16 = indexed - index as u16;
+        cpu.mem_write(0x0600, code);
+        cpu.mem_write_u16(0x0601, base);
+        cpu.mem_write(indexed, 0xEE);
+        cpu.program_counter = 0x0600;
+        // The stored register is 0x05, the other one is the index
+        if code == 0x9E {
+            cpu.register_x = 0x05;
+            cpu.register_y = index;
+        } else {
+            cpu.register_x = index;
+            cpu.register_y = 0x05;
+        }
+        cpu.step();
+
+        assert_eq!(cpu.mem_read(target), value);
+        if target != indexed {
+            assert_eq!(cpu.mem_read(indexed), 0xEE);
+        }
+    }
+
+    // NMOS 6502 cycles for every opcode with no page crossed and no branch
+    // taken, 0 for the ones that halt
+    const BASE_CYCLES: [u8; 256] = [
+        7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0_
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1_
+        6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2_
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3_
+        6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4_
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5_
+        6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6_
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7_
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8_
+        2, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9_
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A_
+        2, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B_
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C_
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D_
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E_
+        2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F_
+    ];
+
+    // Run every opcode from a state where each addressing mode lands on
+    // harmless RAM without crossing a page. All flags but I are clear, so
+    // BPL, BVC, BCC and BNE take their branch.
+    #[test]
+    fn test_opcode_base_cycles() {
+        let taken: [u8; 4] = [0x10, 0x50, 0x90, 0xD0];
+        // BRK also ends step, so it is skipped along with the KIL opcodes
+        for op in opcodes::OPCODES.iter().filter(|op| op.code != 0x00 && BASE_CYCLES[op.code as usize] != 0) {
+            let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+            cpu.mem_write(0x0600, op.code);
+            cpu.mem_write(0x0601, 0x10);
+            cpu.mem_write(0x0602, 0x02);
+            cpu.mem_write_u16(0x0010, 0x0300);
+            cpu.mem_write_u16(0x0210, 0x0400);
+            cpu.program_counter = 0x0600;
+            cpu.register_x = 0x01;
+            cpu.register_y = 0x01;
+
+            let expected: usize = BASE_CYCLES[op.code as usize] as usize + taken.contains(&op.code) as usize;
+            let before: usize = cpu.bus.cycles();
+            assert!(cpu.step(), "{} halted", op.operation);
+            let charged: usize = cpu.bus.cycles() - before;
+            assert_eq!(charged, expected, "{} ({:02X})", op.operation, op.code);
+        }
+    }
+
+    #[test]
+    fn test_bus_trace() {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        // LDA $10, STA $0211, BRK
+        let program: Vec<u8> = vec![0xA5, 0x10, 0x8D, 0x11, 0x02, 0x00];
+        load_bytes(&mut cpu, &program);
+        cpu.mem_write(0x0010, 0x5A);
+        cpu.program_counter = 0x0600;
+
+        let accesses: Rc<RefCell<Vec<BusAccess>>> = Rc::new(RefCell::new(Vec::new()));
+        let log: Rc<RefCell<Vec<BusAccess>>> = accesses.clone();
+        cpu.bus.bus_trace = Some(Box::new(move |access| log.borrow_mut().push(access)));
+        cpu.run();
+
+        let read = |addr: u16, value: u8| BusAccess { addr: addr, value: value, is_write: false };
+        let expected: Vec<BusAccess> = vec![
+            read(0x0600, 0xA5),
+            read(0x0601, 0x10),
+            read(0x0010, 0x5A),
+            read(0x0602, 0x8D),
+            read(0x0603, 0x11),
+            read(0x0604, 0x02),
+            BusAccess { addr: 0x0211, value: 0x5A, is_write: true },
+            read(0x0605, 0x00),
+        ];
+        assert_eq!(*accesses.borrow(), expected);
+    }
+
+    #[test]
+    fn test_irq_serviced_when_unmasked() {
+        let mut bus: Bus = Bus::new(test::test_rom());
+        bus.set_irq_line(IrqSource::MAPPER, true);
+        let mut cpu: CPU = CPU::new(bus);
+
+        // Handler at IRQ vector (0x0101 for test ROM)
+        cpu.mem_write(0x0101, 0xA9);
+        cpu.mem_write(0x0102, 0x07);
+        cpu.mem_write(0x0103, 0x00);
+
+        // CLI, LDA #$01, BRK
+        cpu.mem_write(0x0600, 0x58);
+        cpu.mem_write(0x0601, 0xA9);
+        cpu.mem_write(0x0602, 0x01);
+        cpu.mem_write(0x0603, 0x00);
+        cpu.program_counter = 0x0600;
+        cpu.run();
+
+        assert_eq!(cpu.accumulator, 0x07);
+        assert!(cpu.status.contains(CPUFlags::INT));
+        assert_eq!(cpu.mem_read(0x01FD), 0x06);
+        assert_eq!(cpu.mem_read(0x01FC), 0x01);
+    }
+
+    #[test]
+    fn test_irq_ignored_when_masked() {
+        let mut bus: Bus = Bus::new(test::test_rom());
+        bus.set_irq_line(IrqSource::MAPPER, true);
+        let mut cpu: CPU = CPU::new(bus);
+
+        // SEI, LDA #$01, BRK
+        cpu.mem_write(0x0600, 0x78);
+        cpu.mem_write(0x0601, 0xA9);
+        cpu.mem_write(0x0602, 0x01);
+        cpu.mem_write(0x0603, 0x00);
+        cpu.program_counter = 0x0600;
+        cpu.run();
+
+        assert_eq!(cpu.accumulator, 0x01);
+    }
+
     #[test_case(
         0x02, 0x01, CPUFlags::CARRY;
         "cmp greater"
@@ -1229,6 +2492,34 @@ mod test {
         assert_eq!(cpu.status, CPUFlags::from_bits_truncate(0b0010_0100) | expected_status);
     }
 
+    // CMP, CPX and CPY share cmp, drive each through its immediate opcode.
+    // 0x80 vs 0x01 leaves 0x7F, so carry is set but negative is not.
+    #[test_case(0xC9, 0x00, 0x00, CPUFlags::CARRY | CPUFlags::ZERO; "cmp 0 vs 0")]
+    #[test_case(0xC9, 0x00, 0x01, CPUFlags::NEG; "cmp 0 vs 1")]
+    #[test_case(0xC9, 0x80, 0x01, CPUFlags::CARRY; "cmp 80 vs 1")]
+    #[test_case(0xC9, 0xFF, 0x01, CPUFlags::CARRY | CPUFlags::NEG; "cmp ff vs 1")]
+    #[test_case(0xE0, 0x00, 0x00, CPUFlags::CARRY | CPUFlags::ZERO; "cpx 0 vs 0")]
+    #[test_case(0xE0, 0x00, 0x01, CPUFlags::NEG; "cpx 0 vs 1")]
+    #[test_case(0xE0, 0x80, 0x01, CPUFlags::CARRY; "cpx 80 vs 1")]
+    #[test_case(0xE0, 0xFF, 0x01, CPUFlags::CARRY | CPUFlags::NEG; "cpx ff vs 1")]
+    #[test_case(0xC0, 0x00, 0x00, CPUFlags::CARRY | CPUFlags::ZERO; "cpy 0 vs 0")]
+    #[test_case(0xC0, 0x00, 0x01, CPUFlags::NEG; "cpy 0 vs 1")]
+    #[test_case(0xC0, 0x80, 0x01, CPUFlags::CARRY; "cpy 80 vs 1")]
+    #[test_case(0xC0, 0xFF, 0x01, CPUFlags::CARRY | CPUFlags::NEG; "cpy ff vs 1")]
+    fn test_compare_registers(opcode: u8, register: u8, operand: u8, expected_status: CPUFlags) {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        cpu.accumulator = if opcode == 0xC9 { register } else { 0x42 };
+        cpu.register_x = if opcode == 0xE0 { register } else { 0x42 };
+        cpu.register_y = if opcode == 0xC0 { register } else { 0x42 };
+        cpu.mem_write(0x0600, opcode);
+        cpu.mem_write(0x0601, operand);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        assert_eq!(cpu.status, CPUFlags::from_bits_truncate(0b0010_0100) | expected_status);
+        assert_eq!(cpu.program_counter, 0x0602);
+    }
+
     #[test_case(
         0x02, CPUFlags::empty(), 0x01, CPUFlags::empty();
         "dec no flags"
@@ -1454,6 +2745,59 @@ mod test {
         assert_eq!(cpu.accumulator, 0x05);
     }
 
+    #[test]
+    fn test_jsr_returns_after_operand() {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        // JSR $0610, LDX #$07, BRK with the subroutine LDA #$42, RTS
+        load_bytes(&mut cpu, &[0x20, 0x10, 0x06, 0xA2, 0x07, 0x00]);
+        cpu.mem_write(0x0610, 0xA9);
+        cpu.mem_write(0x0611, 0x42);
+        cpu.mem_write(0x0612, 0x60);
+        cpu.stack_ptr = 0xFD;
+
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0610);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.stack_ptr, 0xFD);
+        cpu.step();
+        assert_eq!(cpu.accumulator, 0x42);
+        assert_eq!(cpu.register_x, 0x07);
+    }
+
+    #[test_case(0x1234, 0x1235; "same page")]
+    #[test_case(0x30FF, 0x3000; "page end")]
+    #[test_case(0xFFFF, 0xFF00; "top of memory")]
+    fn test_jmp_indirect_high_byte(pointer: u16, high_byte_addr: u16) {
+        let mut cpu: CPU = CPU::new(Bus::flat());
+        cpu.mem_write(0x0600, 0x6C);
+        cpu.mem_write_u16(0x0601, pointer);
+        cpu.mem_write(pointer, 0x34);
+        // Where a carrying read would look
+        cpu.mem_write(pointer.wrapping_add(1), 0x99);
+        cpu.mem_write(high_byte_addr, 0x12);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x1234);
+    }
+
+    #[test_case(&[0x4C, 0x01, 0x06], 0x0601; "jmp to operand")]
+    #[test_case(&[0x6C, 0x10, 0x00], 0x0601; "jmp indirect to operand")]
+    #[test_case(&[0x20, 0x01, 0x06], 0x0601; "jsr to operand")]
+    #[test_case(&[0xF0, 0xFF], 0x0601; "branch taken to operand")]
+    #[test_case(&[0xD0, 0xFF], 0x0602; "branch not taken")]
+    fn test_control_flow_to_next_byte(program: &[u8], expected_pc: u16) {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        load_bytes(&mut cpu, program);
+        cpu.mem_write_u16(0x0010, 0x0601);
+        cpu.status.insert(CPUFlags::ZERO);
+        cpu.step();
+        assert_eq!(cpu.program_counter, expected_pc);
+    }
+
     #[test_case(
         0x01, 0x01, CPUFlags::empty();
         "lda no flags"
@@ -1720,11 +3064,56 @@ mod test {
         cpu.accumulator = accumulator;
         cpu.mem_write(0x00, operand as u8);
         cpu.status = initial_status;
+        cpu.program_counter = 0x00;
         cpu.sbc(&AddressingMode::Immediate);
         assert_eq!(cpu.accumulator, expected_acc);
         assert_eq!(cpu.status, expected_status);
     }
 
+    #[test_case(0x69, 0x10, 0x20, false, 0x30, false; "adc pos plus pos")]
+    #[test_case(0x69, 0x50, 0x50, false, 0xA0, true; "adc pos plus pos to neg")]
+    #[test_case(0x69, 0xF0, 0xF0, false, 0xE0, false; "adc neg plus neg")]
+    #[test_case(0x69, 0xD0, 0x90, false, 0x60, true; "adc neg plus neg to pos")]
+    #[test_case(0x69, 0x50, 0xD0, false, 0x20, false; "adc pos plus neg to pos")]
+    #[test_case(0x69, 0x10, 0xD0, false, 0xE0, false; "adc pos plus neg to neg")]
+    #[test_case(0x69, 0x7F, 0x00, true, 0x80, true; "adc carry in overflows")]
+    #[test_case(0xE9, 0x50, 0x30, true, 0x20, false; "sbc pos minus pos to pos")]
+    #[test_case(0xE9, 0x30, 0x50, true, 0xE0, false; "sbc pos minus pos to neg")]
+    #[test_case(0xE9, 0x50, 0xF0, true, 0x60, false; "sbc pos minus neg")]
+    #[test_case(0xE9, 0x50, 0xB0, true, 0xA0, true; "sbc pos minus neg to neg")]
+    #[test_case(0xE9, 0xD0, 0x30, true, 0xA0, false; "sbc neg minus pos")]
+    #[test_case(0xE9, 0xD0, 0x70, true, 0x60, true; "sbc neg minus pos to pos")]
+    #[test_case(0xE9, 0xD0, 0xF0, true, 0xE0, false; "sbc neg minus neg")]
+    #[test_case(0xE9, 0x80, 0x00, false, 0x7F, true; "sbc borrow in overflows")]
+    fn test_overflow_quadrants(code: u8, accumulator: u8, operand: u8, carry: bool, expected_acc: u8, expected_over: bool) {
+        let mut cpu: CPU = CPU::new(Bus::new(test::test_rom()));
+        cpu.mem_write(0x0600, code);
+        cpu.mem_write(0x0601, operand);
+        cpu.program_counter = 0x0600;
+        cpu.accumulator = accumulator;
+        cpu.status.set(CPUFlags::CARRY, carry);
+        cpu.step();
+
+        assert_eq!(cpu.accumulator, expected_acc);
+        assert_eq!(cpu.status.contains(CPUFlags::OVER), expected_over);
+        assert_eq!(cpu.status.contains(CPUFlags::NEG), expected_acc & 0x80 != 0);
+    }
+
+    #[test_case(ATX_MAGIC, 0x01, 0xF0, 0xE0; "Default magic")]
+    #[test_case(0x00, 0x01, 0xF1, 0x01; "Zero magic")]
+    #[test_case(0xFF, 0x00, 0x5A, 0x5A; "Full magic")]
+    fn test_atx_magic(magic: u8, accumulator: u8, operand: u8, expected: u8) {
+        let bus: Bus = Bus::new(test::test_rom());
+        let mut cpu: CPU = CPU::new(bus);
+        cpu.atx_magic = magic;
+        cpu.accumulator = accumulator;
+        cpu.program_counter = 0x0010;
+        cpu.mem_write(0x0010, operand);
+        cpu.atx();
+        assert_eq!(cpu.accumulator, expected);
+        assert_eq!(cpu.register_x, expected);
+    }
+
     #[test]
     fn test_sta() {
         let bus: Bus = Bus::new(test::test_rom());
@@ -0,0 +1,74 @@
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+pub struct Frame {
+    pub data: Vec<u8>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            data: vec![0; WIDTH * HEIGHT * 3],
+        }
+    }
+
+    // Fill every pixel with one colour
+    pub fn clear(&mut self, rgb: (u8, u8, u8)) {
+        for pixel in self.data.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&[rgb.0, rgb.1, rgb.2]);
+        }
+    }
+
+    // 64-bit FNV-1a over the RGB bytes, stable across runs and platforms so
+    // tests can compare against a recorded value
+    pub fn hash(&self) -> u64 {
+        self.data
+            .iter()
+            .fold(FNV_OFFSET, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let base: usize = (y * WIDTH + x) * 3;
+        if base + 2 < self.data.len() {
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        let mut frame: Frame = Frame::new();
+        assert_eq!(frame.hash(), 0x96D6_3225_EA92_6325);
+
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                frame.set_pixel(x, y, (x as u8, y as u8, (x ^ y) as u8));
+            }
+        }
+        assert_eq!(frame.hash(), 0x7958_1EA7_E3C7_D325);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut frame: Frame = Frame::new();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        frame.set_pixel(WIDTH - 1, HEIGHT - 1, (4, 5, 6));
+        frame.clear((7, 8, 9));
+        assert!(frame.data.chunks_exact(3).all(|pixel| pixel == [7, 8, 9]));
+    }
+}
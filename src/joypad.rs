@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+bitflags! {
+    pub struct JoypadButtons: u8 {
+        const RIGHT    = 0b1000_0000;
+        const LEFT     = 0b0100_0000;
+        const DOWN     = 0b0010_0000;
+        const UP       = 0b0001_0000;
+        const START    = 0b0000_1000;
+        const SELECT   = 0b0000_0100;
+        const BUTTON_B = 0b0000_0010;
+        const BUTTON_A = 0b0000_0001;
+    }
+}
+
+// A single controller button, for front-ends that don't want to deal in bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl From<Button> for JoypadButtons {
+    fn from(button: Button) -> Self {
+        match button {
+            Button::A => JoypadButtons::BUTTON_A,
+            Button::B => JoypadButtons::BUTTON_B,
+            Button::Select => JoypadButtons::SELECT,
+            Button::Start => JoypadButtons::START,
+            Button::Up => JoypadButtons::UP,
+            Button::Down => JoypadButtons::DOWN,
+            Button::Left => JoypadButtons::LEFT,
+            Button::Right => JoypadButtons::RIGHT,
+        }
+    }
+}
+
+pub struct Joypad {
+    strobe: bool,
+    button_index: u8,
+    button_status: JoypadButtons,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            button_status: JoypadButtons::empty(),
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0;
+        }
+    }
+
+    // Serially report buttons A, B, Select, Start, Up, Down, Left, Right,
+    // then 1s once all eight have been read
+    pub fn read(&mut self) -> u8 {
+        let response: u8 = self.peek();
+        if self.button_index <= 7 && !self.strobe {
+            self.button_index += 1;
+        }
+        response
+    }
+
+    // Next bit read() would report, without shifting
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits() >> self.button_index) & 1
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: JoypadButtons, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.button_status.insert(button.into());
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.button_status.remove(button.into());
+    }
+
+    // Raw controller byte, bit 0 is A through to bit 7 for Right
+    pub fn set_state(&mut self, buttons: u8) {
+        self.button_status = JoypadButtons::from_bits_truncate(buttons);
+    }
+
+    pub fn buttons(&self) -> JoypadButtons {
+        self.button_status
+    }
+
+    // Replace the button state with whatever the currently held keys map to
+    pub fn update_from_keys<K: Eq + Hash>(&mut self, keymap: &KeyMap<K>, pressed: &[K]) {
+        self.button_status = keymap.translate(pressed);
+    }
+}
+
+// Front-end independent key identifiers, front-ends convert their own key
+// events into these
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Space,
+    RightShift,
+    Letter(char),
+}
+
+pub struct KeyMap<K: Eq + Hash> {
+    bindings: HashMap<K, JoypadButtons>,
+}
+
+impl<K: Eq + Hash> KeyMap<K> {
+    pub fn empty() -> Self {
+        KeyMap {
+            bindings: HashMap::new(),
+        }
+    }
+
+    pub fn bind(&mut self, key: K, button: JoypadButtons) {
+        self.bindings.insert(key, button);
+    }
+
+    pub fn unbind(&mut self, key: &K) {
+        self.bindings.remove(key);
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = (&K, JoypadButtons)> {
+        self.bindings.iter().map(|(key, button)| (key, *button))
+    }
+
+    pub fn button(&self, key: &K) -> Option<JoypadButtons> {
+        self.bindings.get(key).copied()
+    }
+
+    // Combine the buttons of all held keys, unmapped keys are ignored
+    pub fn translate(&self, pressed: &[K]) -> JoypadButtons {
+        pressed
+            .iter()
+            .filter_map(|key| self.button(key))
+            .fold(JoypadButtons::empty(), |acc, button| acc | button)
+    }
+}
+
+impl Default for KeyMap<Key> {
+    fn default() -> Self {
+        let mut keymap: KeyMap<Key> = KeyMap::empty();
+        keymap.bind(Key::Up, JoypadButtons::UP);
+        keymap.bind(Key::Down, JoypadButtons::DOWN);
+        keymap.bind(Key::Left, JoypadButtons::LEFT);
+        keymap.bind(Key::Right, JoypadButtons::RIGHT);
+        keymap.bind(Key::Enter, JoypadButtons::START);
+        keymap.bind(Key::Space, JoypadButtons::SELECT);
+        keymap.bind(Key::Letter('a'), JoypadButtons::BUTTON_A);
+        keymap.bind(Key::Letter('s'), JoypadButtons::BUTTON_B);
+        keymap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_keymap() {
+        let keymap: KeyMap<Key> = KeyMap::default();
+        let buttons: JoypadButtons = keymap.translate(&[Key::Up, Key::Letter('a')]);
+        assert_eq!(buttons, JoypadButtons::UP | JoypadButtons::BUTTON_A);
+    }
+
+    #[test]
+    fn test_custom_keymap() {
+        let mut keymap: KeyMap<u32> = KeyMap::empty();
+        keymap.bind(17, JoypadButtons::UP);
+        keymap.bind(30, JoypadButtons::LEFT);
+        keymap.bind(57, JoypadButtons::BUTTON_A);
+        keymap.bind(28, JoypadButtons::START);
+        keymap.unbind(&28);
+
+        let buttons: JoypadButtons = keymap.translate(&[17, 57, 28, 99]);
+        assert_eq!(buttons, JoypadButtons::UP | JoypadButtons::BUTTON_A);
+
+        let mut joypad: Joypad = Joypad::new();
+        joypad.update_from_keys(&keymap, &[30]);
+        assert_eq!(joypad.buttons(), JoypadButtons::LEFT);
+    }
+
+    #[test]
+    fn test_press_and_release() {
+        let mut joypad: Joypad = Joypad::new();
+        joypad.press(Button::A);
+        joypad.press(Button::Left);
+        joypad.press(Button::Start);
+        assert_eq!(joypad.buttons(), JoypadButtons::BUTTON_A | JoypadButtons::LEFT | JoypadButtons::START);
+
+        joypad.release(Button::Left);
+        joypad.release(Button::Down);
+        assert_eq!(joypad.buttons(), JoypadButtons::BUTTON_A | JoypadButtons::START);
+        assert_eq!(JoypadButtons::from(Button::Select).bits(), 0b0000_0100);
+    }
+
+    #[test]
+    fn test_serial_read() {
+        let mut joypad: Joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButtons::BUTTON_B, true);
+        joypad.set_button_pressed_status(JoypadButtons::START, true);
+        joypad.write(1);
+        joypad.write(0);
+
+        let reads: Vec<u8> = (0..9).map(|_| joypad.read()).collect();
+        assert_eq!(reads, vec![0, 1, 0, 1, 0, 0, 0, 0, 1]);
+    }
+}
@@ -1,10 +1,24 @@
+pub mod apu;
 pub mod bus;
 pub mod rom;
+pub mod cartridge;
+pub mod mapper;
 pub mod mem;
 pub mod cpu;
 pub mod ppu;
 pub mod opcodes;
+pub mod asm;
 pub mod trace;
+pub mod joypad;
+pub mod frame;
+pub mod palette;
+pub mod render;
+pub mod config;
+pub mod console;
+#[cfg(feature = "profile")]
+pub mod profile;
+#[cfg(all(test, feature = "json"))]
+mod single_step;
 
 use bus::Bus;
 use rom::Rom;
@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::rom::{Mirroring, Rom};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const CHR_HALF_BANK_SIZE: usize = 0x1000;
+const PRG_32K_BANK_SIZE: usize = 0x8000;
+
+// Cartridge hardware deciding which PRG/CHR bytes the CPU and PPU see
+pub trait Mapper {
+    fn read_prg(&self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, data: u8);
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, data: u8);
+
+    // Nametable layout when the cart controls it, otherwise the header's is used
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+}
+
+// Shared between the bus (PRG side) and the PPU (CHR side)
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+// Mapper numbers from_rom has a real implementation for, anything else
+// falls back to NROM and probably won't run right
+pub fn is_mapper_supported(mapper: u8) -> bool {
+    matches!(mapper, 0 | 1 | 3 | 7)
+}
+
+pub fn from_rom(rom: &Rom) -> SharedMapper {
+    match rom.mapper {
+        1 => Rc::new(RefCell::new(Mmc1Mapper::new(rom.prg.clone(), rom.chr.clone()))),
+        3 => Rc::new(RefCell::new(CnromMapper::new(rom.prg.clone(), rom.chr.clone()))),
+        7 => Rc::new(RefCell::new(AxromMapper::new(rom.prg.clone(), rom.chr.clone()))),
+        _ => Rc::new(RefCell::new(NromMapper::new(rom.prg.clone(), rom.chr.clone()))),
+    }
+}
+
+// Carts without CHR ROM come with 8KB of CHR RAM instead
+fn chr_or_ram(chr: Vec<u8>) -> (Vec<u8>, bool) {
+    if chr.is_empty() {
+        (vec![0; CHR_BANK_SIZE], true)
+    } else {
+        (chr, false)
+    }
+}
+
+// Map 0x8000-0xFFFF into PRG, repeating carts smaller than 32KB so a
+// single 16KB bank also shows up at 0xC000
+fn read_fixed_prg(prg: &[u8], addr: u16) -> u8 {
+    let offset: usize = (addr - 0x8000) as usize;
+    prg[offset % prg.len()]
+}
+
+// Mapper 0, no bank switching
+pub struct NromMapper {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+}
+
+impl NromMapper {
+    pub fn new(prg: Vec<u8>, chr: Vec<u8>) -> Self {
+        let (chr, chr_ram): (Vec<u8>, bool) = chr_or_ram(chr);
+        NromMapper {
+            prg: prg,
+            chr: chr,
+            chr_ram: chr_ram,
+        }
+    }
+}
+
+impl Mapper for NromMapper {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_fixed_prg(&self.prg, addr)
+    }
+
+    fn write_prg(&mut self, _addr: u16, _data: u8) {}
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let len: usize = self.chr.len();
+            self.chr[addr as usize % len] = data;
+        }
+    }
+}
+
+// Mapper 3, fixed PRG with any write to cartridge space selecting an 8KB CHR bank
+pub struct CnromMapper {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_bank: usize,
+}
+
+impl CnromMapper {
+    pub fn new(prg: Vec<u8>, chr: Vec<u8>) -> Self {
+        let (chr, _): (Vec<u8>, bool) = chr_or_ram(chr);
+        CnromMapper {
+            prg: prg,
+            chr: chr,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for CnromMapper {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_fixed_prg(&self.prg, addr)
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        let banks: usize = (self.chr.len() / CHR_BANK_SIZE).max(1);
+        self.chr_bank = data as usize % banks;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_bank * CHR_BANK_SIZE + (addr as usize % CHR_BANK_SIZE)]
+    }
+
+    fn write_chr(&mut self, _addr: u16, _data: u8) {}
+}
+
+// Mapper 1, registers are loaded serially one bit per write. PRG can switch
+// as a 32KB block or as one 16KB bank with the other fixed, CHR as one 8KB
+// or two 4KB banks.
+pub struct Mmc1Mapper {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1Mapper {
+    pub fn new(prg: Vec<u8>, chr: Vec<u8>) -> Self {
+        let (chr, chr_ram): (Vec<u8>, bool) = chr_or_ram(chr);
+        Mmc1Mapper {
+            prg: prg,
+            chr: chr,
+            chr_ram: chr_ram,
+            shift: 0,
+            shift_count: 0,
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let addr: usize = addr as usize & 0x1FFF;
+        let index: usize = if self.control & 0x10 == 0 {
+            // Single 8KB bank, low bit ignored
+            (self.chr_bank_0 & 0x1E) as usize * CHR_HALF_BANK_SIZE + addr
+        } else if addr < CHR_HALF_BANK_SIZE {
+            self.chr_bank_0 as usize * CHR_HALF_BANK_SIZE + addr
+        } else {
+            self.chr_bank_1 as usize * CHR_HALF_BANK_SIZE + (addr - CHR_HALF_BANK_SIZE)
+        };
+        index % self.chr.len()
+    }
+}
+
+impl Mapper for Mmc1Mapper {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank_count: usize = (self.prg.len() / PRG_BANK_SIZE).max(1);
+        let upper: bool = addr >= 0xC000;
+        let bank: usize = match (self.control >> 2) & 0b11 {
+            0 | 1 => (self.prg_bank & 0x0E) as usize + upper as usize,
+            2 => if upper { self.prg_bank as usize } else { 0 },
+            _ => if upper { bank_count - 1 } else { self.prg_bank as usize },
+        };
+        self.prg[(bank % bank_count) * PRG_BANK_SIZE + (addr as usize & 0x3FFF)]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        // Bit 7 resets the shift register and locks the last PRG bank
+        if data & 0x80 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        self.shift |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return;
+        }
+
+        // Fifth write picks the register from the address
+        match (addr >> 13) & 0b11 {
+            0 => self.control = self.shift,
+            1 => self.chr_bank_0 = self.shift,
+            2 => self.chr_bank_1 = self.shift,
+            _ => self.prg_bank = self.shift & 0x0F,
+        }
+        self.shift = 0;
+        self.shift_count = 0;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let index: usize = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+}
+
+// Mapper 7, writes to cartridge space pick a 32KB PRG bank with bits 0-2
+// and which of the two nametables fills all four screens with bit 4
+pub struct AxromMapper {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_bank: usize,
+    upper_nametable: bool,
+}
+
+impl AxromMapper {
+    pub fn new(prg: Vec<u8>, chr: Vec<u8>) -> Self {
+        let (chr, chr_ram): (Vec<u8>, bool) = chr_or_ram(chr);
+        AxromMapper {
+            prg: prg,
+            chr: chr,
+            chr_ram: chr_ram,
+            prg_bank: 0,
+            upper_nametable: false,
+        }
+    }
+}
+
+impl Mapper for AxromMapper {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank_count: usize = (self.prg.len() / PRG_32K_BANK_SIZE).max(1);
+        let index: usize = (self.prg_bank % bank_count) * PRG_32K_BANK_SIZE + (addr - 0x8000) as usize;
+        self.prg[index % self.prg.len()]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.prg_bank = (data & 0b111) as usize;
+        self.upper_nametable = data & 0b1_0000 != 0;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let len: usize = self.chr.len();
+            self.chr[addr as usize % len] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        if self.upper_nametable {
+            Some(Mirroring::SINGLE_SCREEN_UPPER)
+        } else {
+            Some(Mirroring::SINGLE_SCREEN_LOWER)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn banked_chr(banks: usize) -> Vec<u8> {
+        let mut chr: Vec<u8> = vec![];
+        for bank in 0..banks {
+            chr.extend(vec![bank as u8; CHR_BANK_SIZE]);
+        }
+        chr
+    }
+
+    // PRG where each 16KB bank is filled with its index, plus a marker in
+    // the last byte of the cart
+    fn banked_prg(banks: usize) -> Vec<u8> {
+        let mut prg: Vec<u8> = vec![];
+        for bank in 0..banks {
+            prg.extend(vec![bank as u8; PRG_BANK_SIZE]);
+        }
+        let last: usize = prg.len() - 1;
+        prg[last] = 0xEE;
+        prg
+    }
+
+    #[test_case(1, 0x8000, 0; "16k start")]
+    #[test_case(1, 0xC000, 0; "16k mirrored")]
+    #[test_case(1, 0xBFFF, 0xEE; "16k end")]
+    #[test_case(1, 0xFFFF, 0xEE; "16k mirrored end")]
+    #[test_case(2, 0x8000, 0; "32k start")]
+    #[test_case(2, 0xC000, 1; "32k upper bank")]
+    #[test_case(2, 0xBFFF, 0; "32k lower end")]
+    #[test_case(2, 0xFFFF, 0xEE; "32k end")]
+    fn test_nrom_prg_mirroring(banks: usize, addr: u16, expected: u8) {
+        let mapper: NromMapper = NromMapper::new(banked_prg(banks), vec![]);
+        assert_eq!(mapper.read_prg(addr), expected);
+    }
+
+    #[test_case(0, true)]
+    #[test_case(1, true)]
+    #[test_case(3, true)]
+    #[test_case(7, true)]
+    #[test_case(4, false)]
+    #[test_case(255, false)]
+    fn test_is_mapper_supported(mapper: u8, expected: bool) {
+        assert_eq!(is_mapper_supported(mapper), expected);
+    }
+
+    #[test]
+    fn test_nrom_chr_ram_when_no_chr_rom() {
+        let mut mapper: NromMapper = NromMapper::new(vec![0; PRG_BANK_SIZE], vec![]);
+        mapper.write_chr(0x0123, 0x45);
+        assert_eq!(mapper.read_chr(0x0123), 0x45);
+    }
+
+    #[test]
+    fn test_nrom_chr_rom_read_only() {
+        let mut mapper: NromMapper = NromMapper::new(vec![0; PRG_BANK_SIZE], vec![7; CHR_BANK_SIZE]);
+        mapper.write_chr(0x0123, 0x45);
+        assert_eq!(mapper.read_chr(0x0123), 7);
+    }
+
+    #[test]
+    fn test_cnrom_bank_select() {
+        let mut mapper: CnromMapper = CnromMapper::new(vec![0; PRG_BANK_SIZE], banked_chr(4));
+        assert_eq!(mapper.read_chr(0x1FFF), 0);
+        mapper.write_prg(0x8000, 2);
+        assert_eq!(mapper.read_chr(0x0000), 2);
+        mapper.write_prg(0xFFFF, 7);
+        assert_eq!(mapper.read_chr(0x1000), 3);
+    }
+
+    #[test]
+    fn test_axrom_bank_and_nametable_select() {
+        let mut mapper: AxromMapper = AxromMapper::new(banked_prg(4), vec![]);
+        assert_eq!(mapper.read_prg(0x8000), 0);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::SINGLE_SCREEN_LOWER));
+
+        mapper.write_prg(0x8000, 0b1_0001);
+        assert_eq!(mapper.read_prg(0x8000), 2);
+        assert_eq!(mapper.read_prg(0xC000), 3);
+        assert_eq!(mapper.read_prg(0xFFFF), 0xEE);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::SINGLE_SCREEN_UPPER));
+    }
+
+    // Load an MMC1 register through five serial writes
+    pub fn mmc1_write(mapper: &mut dyn FnMut(u16, u8), addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper(addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_chr_4k_banks() {
+        let mut mapper: Mmc1Mapper = Mmc1Mapper::new(vec![0; PRG_BANK_SIZE * 2], banked_chr(2));
+        mmc1_write(&mut |a, d| mapper.write_prg(a, d), 0x8000, 0x1C);
+        mmc1_write(&mut |a, d| mapper.write_prg(a, d), 0xA000, 3);
+        mmc1_write(&mut |a, d| mapper.write_prg(a, d), 0xC000, 0);
+        assert_eq!(mapper.read_chr(0x0000), 1);
+        assert_eq!(mapper.read_chr(0x1000), 0);
+    }
+
+    #[test]
+    fn test_mmc1_reset_bit() {
+        let mut mapper: Mmc1Mapper = Mmc1Mapper::new(vec![0; PRG_BANK_SIZE * 2], vec![]);
+        mapper.write_prg(0x8000, 1);
+        mapper.write_prg(0x8000, 0x80);
+        assert_eq!(mapper.shift_count, 0);
+        assert_eq!(mapper.control & 0x0C, 0x0C);
+    }
+}
@@ -1,17 +1,66 @@
 pub trait Mem {
-    fn mem_read(&self, addr: u16) -> u8;
+    fn mem_read(&mut self, addr: u16) -> u8;
     fn mem_write(&mut self, addr: u16, data: u8);
-    
-    fn mem_read_u16(&self, addr: u16) -> u16 {
+
+    // Read without side effects, for tracing and debuggers
+    fn peek(&self, addr: u16) -> u8;
+
+    // 16-bit values are little-endian. The high byte comes from addr + 1
+    // wrapping over the full address space, so 0xFFFF pairs with 0x0000.
+    // Zero page pointers that wrap within page 0 are handled by the CPU.
+    fn mem_read_u16(&mut self, addr: u16) -> u16 {
         let low: u16 = self.mem_read(addr) as u16;
-        let high: u16 = self.mem_read(addr + 1) as u16;
+        let high: u16 = self.mem_read(addr.wrapping_add(1)) as u16;
         return (high << 8) | low;
     }
 
+    fn peek_u16(&self, addr: u16) -> u16 {
+        let low: u16 = self.peek(addr) as u16;
+        let high: u16 = self.peek(addr.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
     fn mem_write_u16(&mut self, addr: u16, data: u16) {
         let high: u8 = (data >> 8) as u8;
         let low: u8 = (data & 0xff) as u8;
         self.mem_write(addr, low);
-        self.mem_write(addr + 1, high);
+        self.mem_write(addr.wrapping_add(1), high);
+    }
+}
+
+// 64KB of plain RAM with no devices mapped, for CPU conformance tests
+#[cfg(test)]
+pub struct FlatMemory {
+    data: Vec<u8>,
+}
+
+#[cfg(test)]
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory {
+            data: vec![0; 0x10000],
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl Mem for FlatMemory {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.data[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.data[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.data[addr as usize]
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use lazy_static;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -17,24 +18,45 @@ pub enum AddressingMode {
     NoneAddressing,
 }
 
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct OpCode {
     pub code: u8,
     pub operation: &'static str,
     pub len: u8,
     pub cycles: u8,
     pub mode: AddressingMode,
+    // One more cycle when an indexed read crosses a page
+    pub page_cross_penalty: bool,
 }
 
+// Reads only touch the indexed address once, so a carry into the high byte
+// costs an extra cycle. Stores and read-modify-writes always spend that cycle
+// and have it in their base count.
+const PAGE_CROSS_READS: [&str; 12] = [
+    "ADC", "AND", "CMP", "EOR", "LDA", "LDX", "LDY", "ORA", "SBC", "*LAX", "*LAS", "*NOP",
+];
+
 impl OpCode {
     fn new(code: u8, operation: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self {
+        let indexed: bool = matches!(
+            mode,
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y | AddressingMode::Indirect_Y
+        );
         OpCode {
             code: code,
             operation: operation,
             len: len,
             cycles: cycles,
             mode: mode,
+            page_cross_penalty: indexed && PAGE_CROSS_READS.contains(&operation),
         }
     }
+
+    // Undocumented opcodes are marked with a * in the mnemonic, KIL jams
+    // the chip and is unofficial too
+    pub fn is_illegal(&self) -> bool {
+        self.operation.starts_with('*') || self.operation == "KIL"
+    }
 }
 
 lazy_static! {
@@ -375,4 +397,76 @@ lazy_static! {
         }
         map
     };
-}
\ No newline at end of file
+
+    // Indexed by opcode byte for dispatch without hashing
+    pub static ref OPCODE_TABLE: [Option<&'static OpCode>; 256] = {
+        let mut table: [Option<&'static OpCode>; 256] = [None; 256];
+        for op in &*OPCODES {
+            table[op.code as usize] = Some(op);
+        }
+        table
+    };
+}
+
+// Serialize the full opcode table for external tooling
+#[cfg(feature = "json")]
+pub fn opcode_table_json() -> String {
+    serde_json::to_string(&*OPCODES).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_page_cross_penalty() {
+        let expected: Vec<u8> = vec![
+            0x11, 0x19, 0x1C, 0x1D, 0x31, 0x39, 0x3C, 0x3D, 0x51, 0x59, 0x5C, 0x5D, 0x71, 0x79,
+            0x7C, 0x7D, 0xB1, 0xB3, 0xB9, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF, 0xD1, 0xD9, 0xDC, 0xDD,
+            0xF1, 0xF9, 0xFC, 0xFD,
+        ];
+        let mut flagged: Vec<u8> = OPCODES.iter().filter(|op| op.page_cross_penalty).map(|op| op.code).collect();
+        flagged.sort();
+        assert_eq!(flagged, expected);
+    }
+
+    #[test]
+    fn test_illegal_opcodes() {
+        let legal: usize = OPCODES.iter().filter(|op| !op.is_illegal()).count();
+        assert_eq!(legal, 151);
+        assert!(OPCODE_TABLE[0x07].unwrap().is_illegal());
+        assert!(OPCODE_TABLE[0xEB].unwrap().is_illegal());
+        assert!(!OPCODE_TABLE[0xEA].unwrap().is_illegal());
+    }
+
+    #[test]
+    fn test_table_matches_map() {
+        for code in 0..=255u8 {
+            let from_table: Option<&OpCode> = OPCODE_TABLE[code as usize];
+            let from_map: Option<&OpCode> = OPCODES_MAP.get(&code).copied();
+            match (from_table, from_map) {
+                (Some(a), Some(b)) => assert!(std::ptr::eq(a, b)),
+                (None, None) => {}
+                _ => panic!("OpCode {:02x} differs between table and map", code),
+            }
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_opcode_table_json() {
+        let json: serde_json::Value = serde_json::from_str(&opcode_table_json()).unwrap();
+        let entries: &Vec<serde_json::Value> = json.as_array().unwrap();
+        assert_eq!(entries.len(), OPCODES.len());
+
+        let adc: &serde_json::Value = entries.iter().find(|op| op["code"] == 0x69).unwrap();
+        assert_eq!(adc["operation"], "ADC");
+        assert_eq!(adc["len"], 2);
+        assert_eq!(adc["cycles"], 2);
+        assert_eq!(adc["mode"], "Immediate");
+
+        let jmp: &serde_json::Value = entries.iter().find(|op| op["code"] == 0x6C).unwrap();
+        assert_eq!(jmp["operation"], "JMP");
+        assert_eq!(jmp["len"], 3);
+    }
+}
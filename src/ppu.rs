@@ -1,21 +1,1128 @@
+use crate::cartridge::SharedCartridge;
 use crate::rom::Mirroring;
+#[cfg(test)]
+use crate::cartridge::Cartridge;
+#[cfg(test)]
+use crate::rom::Rom;
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::rc::Rc;
+
+const DOTS_PER_SCANLINE: usize = 341;
+const VISIBLE_SCANLINES: u16 = 240;
+const PRE_RENDER_SCANLINE: u16 = 261;
+// Vblank starts on dot 1 of the line after the post-render line and ends on
+// dot 1 of the pre-render line
+pub const VBLANK_SET_SCANLINE: u16 = 241;
+pub const VBLANK_CLEAR_SCANLINE: u16 = PRE_RENDER_SCANLINE;
+pub const VBLANK_DOT: usize = 1;
+// After power-on or reset the PPU ignores $2000, $2001, $2005 and $2006
+// writes for about 29658 CPU cycles
+pub const WARMUP_DOTS: usize = 29658 * 3;
+
+bitflags! {
+    pub struct ControlRegister: u8 {
+        const NAMETABLE_LO            = 0b0000_0001;
+        const NAMETABLE_HI            = 0b0000_0010;
+        const VRAM_INCREMENT          = 0b0000_0100;
+        const SPRITE_PATTERN_ADDR     = 0b0000_1000;
+        const BACKGROUND_PATTERN_ADDR = 0b0001_0000;
+        const SPRITE_SIZE             = 0b0010_0000;
+        const MASTER_SLAVE_SELECT     = 0b0100_0000;
+        const GENERATE_NMI            = 0b1000_0000;
+    }
+}
+
+impl ControlRegister {
+    pub fn base_nametable_addr(&self) -> u16 {
+        0x2000 + (self.bits() & 0b11) as u16 * 0x400
+    }
+
+    // $2007 steps across a row, or down a column of the nametable
+    pub fn vram_increment(&self) -> u8 {
+        if self.contains(ControlRegister::VRAM_INCREMENT) {
+            32
+        } else {
+            1
+        }
+    }
+
+    // Pattern table for 8x8 sprites, 8x16 sprites ignore it
+    pub fn sprite_pattern_addr(&self) -> u16 {
+        if self.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0
+        }
+    }
+
+    pub fn background_pattern_addr(&self) -> u16 {
+        if self.contains(ControlRegister::BACKGROUND_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0
+        }
+    }
+
+    pub fn nmi_enabled(&self) -> bool {
+        self.contains(ControlRegister::GENERATE_NMI)
+    }
+
+    pub fn sprite_size(&self) -> u8 {
+        if self.contains(ControlRegister::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+}
+
+bitflags! {
+    pub struct MaskRegister: u8 {
+        const GREYSCALE            = 0b0000_0001;
+        const SHOW_BACKGROUND_LEFT = 0b0000_0010;
+        const SHOW_SPRITES_LEFT    = 0b0000_0100;
+        const SHOW_BACKGROUND      = 0b0000_1000;
+        const SHOW_SPRITES         = 0b0001_0000;
+        const EMPHASISE_RED        = 0b0010_0000;
+        const EMPHASISE_GREEN      = 0b0100_0000;
+        const EMPHASISE_BLUE       = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    pub struct StatusRegister: u8 {
+        const SPRITE_OVERFLOW = 0b0010_0000;
+        const SPRITE_ZERO_HIT = 0b0100_0000;
+        const VBLANK_STARTED  = 0b1000_0000;
+    }
+}
+
+// Palette RAM repeats every 32 bytes up to 0x3FFF, and the colour 0
+// entries of the sprite palettes share the background ones. 0x3F10 is
+// the universal backdrop at 0x3F00.
+fn mirror_palette_addr(addr: u16) -> usize {
+    let index: usize = (addr & 0x1F) as usize;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+        _ => index,
+    }
+}
+
+// One sprite's four OAM bytes
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OamEntry {
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    pub x: u8,
+}
+
+impl OamEntry {
+    // Sprite palette 0-3, colours start at $3F10 + palette * 4
+    pub fn palette(&self) -> u8 {
+        self.attributes & 0b11
+    }
+
+    // Priority bit, set when the sprite goes behind opaque background
+    pub fn behind_background(&self) -> bool {
+        self.attributes & 0b0010_0000 != 0
+    }
+
+    pub fn flip_h(&self) -> bool {
+        self.attributes & 0b0100_0000 != 0
+    }
+
+    pub fn flip_v(&self) -> bool {
+        self.attributes & 0b1000_0000 != 0
+    }
+}
+
+// A CPU access to one of the eight PPU registers, with the dot it landed on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RegAccess {
+    pub reg: u8,
+    pub value: u8,
+    pub is_write: bool,
+    pub scanline: u16,
+    pub dot: u16,
+}
+
+// Register accesses hardware silently ignores or garbles, reported in
+// strict mode
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AccessViolation {
+    // Read of $2000, $2001, $2003, $2005 or $2006
+    WriteOnlyRead,
+    // Write to $2002
+    ReadOnlyWrite,
+    // Write to $2000, $2001, $2005 or $2006 before the PPU has warmed up
+    WriteDuringWarmup,
+    // $2004 or $2007 write while the PPU is fetching for the picture
+    WriteWhileRendering,
+}
 
 pub struct PPU {
-    pub chr_rom: Vec<u8>,
+    pub cartridge: SharedCartridge,
     pub palette_table: [u8; 32],
     pub ram: [u8; 2048],
     pub oam: [u8; 256],
     pub mirroring: Mirroring,
+    pub ctrl: ControlRegister,
+    pub mask: MaskRegister,
+    pub status: StatusRegister,
+    pub oam_addr: u8,
+    open_bus: u8,
+    // Internal v, t, x and w registers shared by $2000, $2005 and $2006.
+    // v and t are packed as 0yyy NNYY YYYX XXXX: fine Y, nametable,
+    // coarse Y and coarse X.
+    vram_addr: u16,
+    temp_addr: u16,
+    fine_x: u8,
+    write_latch: bool,
+    data_buffer: u8,
+    scanline: u16,
+    dot: usize,
+    frame_complete: bool,
+    odd_frame: bool,
+    nmi_pending: bool,
+    warmup_dots: usize,
+    pub on_register_access: Option<Box<dyn FnMut(RegAccess)>>,
+    strict_hook: Option<Box<dyn FnMut(AccessViolation, RegAccess)>>,
 }
 
 impl PPU {
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn new(cartridge: SharedCartridge, mirroring: Mirroring) -> Self {
         PPU {
-            chr_rom: chr_rom,
+            cartridge: cartridge,
             palette_table: [0; 32],
             ram: [0; 2048],
             oam: [0; 256],
             mirroring: mirroring,
+            ctrl: ControlRegister::empty(),
+            mask: MaskRegister::empty(),
+            status: StatusRegister::empty(),
+            oam_addr: 0,
+            open_bus: 0,
+            vram_addr: 0,
+            temp_addr: 0,
+            fine_x: 0,
+            write_latch: false,
+            data_buffer: 0,
+            scanline: 0,
+            dot: 0,
+            frame_complete: false,
+            odd_frame: false,
+            nmi_pending: false,
+            warmup_dots: 0,
+            on_register_access: None,
+            strict_hook: None,
+        }
+    }
+
+    // Reset button, the control and mask registers are cleared and
+    // rendering restarts from the top of the frame
+    pub fn reset(&mut self) {
+        self.ctrl = ControlRegister::empty();
+        self.mask = MaskRegister::empty();
+        self.write_latch = false;
+        self.scanline = 0;
+        self.dot = 0;
+        self.frame_complete = false;
+        self.odd_frame = false;
+        self.nmi_pending = false;
+        self.start_warmup();
+    }
+
+    // Ignore the early register writes real hardware drops. A bare PPU
+    // starts warm, the bus starts the warm-up at power-on.
+    pub fn start_warmup(&mut self) {
+        self.warmup_dots = WARMUP_DOTS;
+    }
+
+    pub fn skip_warmup(&mut self) {
+        self.warmup_dots = 0;
+    }
+
+    pub fn warming_up(&self) -> bool {
+        self.warmup_dots > 0
+    }
+
+    // CPU access to $2000-$2007. Every access drives the PPU's data bus, and
+    // write-only registers read back whatever was left on it.
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match addr {
+            0x2002 => (self.status.bits() & 0xE0) | (self.open_bus & 0x1F),
+            0x2004 => self.read_oam_data(),
+            // Palette reads skip the buffer, everything else returns the
+            // byte fetched by the previous read
+            0x2007 if self.bus_addr() >= 0x3F00 => self.read_vram(self.bus_addr()),
+            0x2007 => self.data_buffer,
+            _ => self.open_bus,
+        }
+    }
+
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        let data: u8 = self.peek_register(addr);
+        if matches!(addr, 0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006) {
+            self.report_violation(AccessViolation::WriteOnlyRead, addr, data, false);
+        }
+        self.open_bus = data;
+        // Reading status acknowledges vblank and restarts the $2005/$2006
+        // write pair
+        if addr == 0x2002 {
+            self.status.remove(StatusRegister::VBLANK_STARTED);
+            self.write_latch = false;
+        }
+        if addr == 0x2007 {
+            // Palette reads still refill the buffer, from the nametable
+            // underneath them
+            let buffer_addr: u16 = if self.bus_addr() >= 0x3F00 {
+                self.bus_addr() - 0x1000
+            } else {
+                self.bus_addr()
+            };
+            self.data_buffer = self.read_vram(buffer_addr);
+            self.increment_vram_addr();
+        }
+        self.log_register_access(addr, data, false);
+        data
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        self.log_register_access(addr, data, true);
+        self.open_bus = data;
+        if addr == 0x2002 {
+            self.report_violation(AccessViolation::ReadOnlyWrite, addr, data, true);
+        }
+        if matches!(addr, 0x2004 | 0x2007) && self.rendering_enabled() && self.fetching() {
+            self.report_violation(AccessViolation::WriteWhileRendering, addr, data, true);
+        }
+        // Still warming up, the write only reaches the data bus
+        if self.warming_up() && matches!(addr, 0x2000 | 0x2001 | 0x2005 | 0x2006) {
+            self.report_violation(AccessViolation::WriteDuringWarmup, addr, data, true);
+            return;
+        }
+        match addr {
+            0x2000 => self.write_to_ctrl(data),
+            0x2001 => self.write_to_mask(data),
+            0x2003 => self.write_to_oam_addr(data),
+            0x2004 => self.write_to_oam_data(data),
+            0x2005 => self.write_to_scroll(data),
+            0x2006 => self.write_to_addr(data),
+            0x2007 => self.write_to_data(data),
+            _ => {}
+        }
+    }
+
+    fn log_register_access(&mut self, addr: u16, value: u8, is_write: bool) {
+        if let Some(hook) = self.on_register_access.as_mut() {
+            hook(RegAccess {
+                reg: (addr & 0x07) as u8,
+                value: value,
+                is_write: is_write,
+                scanline: self.scanline,
+                dot: self.dot as u16,
+            });
+        }
+    }
+
+    // Strict mode, call `hook` for every access real hardware would ignore
+    // or mishandle. Off until a hook is set.
+    pub fn set_strict_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(AccessViolation, RegAccess) + 'static,
+    {
+        self.strict_hook = Some(Box::new(hook));
+    }
+
+    fn report_violation(&mut self, violation: AccessViolation, addr: u16, value: u8, is_write: bool) {
+        if let Some(hook) = self.strict_hook.as_mut() {
+            hook(violation, RegAccess {
+                reg: (addr & 0x07) as u8,
+                value: value,
+                is_write: is_write,
+                scanline: self.scanline,
+                dot: self.dot as u16,
+            });
+        }
+    }
+
+    // Visible and pre-render lines, where the PPU owns its address bus
+    fn fetching(&self) -> bool {
+        self.scanline < VISIBLE_SCANLINES || self.scanline == PRE_RENDER_SCANLINE
+    }
+
+    // Turning NMI on while vblank is already flagged fires one straight away
+    pub fn write_to_ctrl(&mut self, value: u8) {
+        let was_enabled: bool = self.ctrl.nmi_enabled();
+        self.ctrl = ControlRegister::from_bits_truncate(value);
+        // Base nametable goes to t bits 10-11
+        self.temp_addr = (self.temp_addr & !0x0C00) | ((value as u16 & 0b11) << 10);
+        if !was_enabled && self.ctrl.nmi_enabled() && self.status.contains(StatusRegister::VBLANK_STARTED) {
+            self.nmi_pending = true;
+        }
+    }
+
+    // Returns true once for each NMI the PPU has raised
+    pub fn poll_nmi(&mut self) -> bool {
+        let pending: bool = self.nmi_pending;
+        self.nmi_pending = false;
+        pending
+    }
+
+    pub fn write_to_mask(&mut self, value: u8) {
+        self.mask = MaskRegister::from_bits_truncate(value);
+    }
+
+    pub fn write_to_oam_addr(&mut self, value: u8) {
+        self.oam_addr = value;
+    }
+
+    pub fn write_to_oam_data(&mut self, value: u8) {
+        self.oam[self.oam_addr as usize] = value;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    pub fn read_oam_data(&self) -> u8 {
+        self.oam[self.oam_addr as usize]
+    }
+
+    // Sprite 0-63 from OAM
+    pub fn sprite(&self, index: usize) -> OamEntry {
+        let bytes: &[u8] = &self.oam[index * 4..index * 4 + 4];
+        OamEntry {
+            y: bytes[0],
+            tile: bytes[1],
+            attributes: bytes[2],
+            x: bytes[3],
+        }
+    }
+
+    // $2005 takes X scroll first, split into coarse X in t and fine X,
+    // then Y scroll, split into coarse and fine Y in t
+    pub fn write_to_scroll(&mut self, value: u8) {
+        if !self.write_latch {
+            self.temp_addr = (self.temp_addr & !0x001F) | (value >> 3) as u16;
+            self.fine_x = value & 0b111;
+        } else {
+            self.temp_addr = (self.temp_addr & !0x73E0)
+                | ((value as u16 & 0b111) << 12)
+                | ((value as u16 & 0xF8) << 2);
+        }
+        self.write_latch = !self.write_latch;
+    }
+
+    // $2006 takes the high byte first, which fills t bits 8-13 and clears
+    // bit 14, then the low byte, which completes t and copies it to v
+    pub fn write_to_addr(&mut self, value: u8) {
+        if !self.write_latch {
+            self.temp_addr = (self.temp_addr & 0x00FF) | ((value as u16 & 0x3F) << 8);
+        } else {
+            self.temp_addr = (self.temp_addr & 0xFF00) | value as u16;
+            self.vram_addr = self.temp_addr;
+        }
+        self.write_latch = !self.write_latch;
+    }
+
+    // Scroll as last written through $2000/$2005/$2006, in pixels
+    pub fn scroll(&self) -> (u16, u16) {
+        let coarse_x: u16 = self.temp_addr & 0x1F;
+        let coarse_y: u16 = (self.temp_addr >> 5) & 0x1F;
+        let fine_y: u16 = (self.temp_addr >> 12) & 0b111;
+        let nametable_x: u16 = (self.temp_addr >> 10) & 1;
+        let nametable_y: u16 = (self.temp_addr >> 11) & 1;
+        (nametable_x * 256 + coarse_x * 8 + self.fine_x as u16, nametable_y * 240 + coarse_y * 8 + fine_y)
+    }
+
+    pub fn write_to_data(&mut self, value: u8) {
+        let addr: u16 = self.bus_addr();
+        match addr {
+            0x0000..=0x1FFF => self.cartridge.borrow_mut().write_ppu(addr, value),
+            0x2000..=0x3EFF => self.ram[self.mirror_vram_addr(addr) as usize] = value,
+            _ => self.palette_table[mirror_palette_addr(addr)] = value,
         }
+        self.increment_vram_addr();
     }
- }
\ No newline at end of file
+
+    fn read_vram(&self, addr: u16) -> u8 {
+        let addr: u16 = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => self.chr_read(addr),
+            0x2000..=0x3EFF => self.ram[self.mirror_vram_addr(addr) as usize],
+            _ => self.read_palette(addr),
+        }
+    }
+
+    pub fn read_palette(&self, addr: u16) -> u8 {
+        self.palette_table[mirror_palette_addr(addr)]
+    }
+
+    // v drives a 14 bit address bus, bit 14 is only fine Y for rendering
+    fn bus_addr(&self) -> u16 {
+        self.vram_addr & 0x3FFF
+    }
+
+    fn increment_vram_addr(&mut self) {
+        self.vram_addr = self.vram_addr.wrapping_add(self.ctrl.vram_increment() as u16) & 0x3FFF;
+    }
+
+    // Pattern table fetch, the cartridge decides which CHR bank is visible
+    pub fn chr_read(&self, addr: u16) -> u8 {
+        self.cartridge.borrow().read_ppu(addr)
+    }
+
+    pub fn show_background_left8(&self) -> bool {
+        self.mask.contains(MaskRegister::SHOW_BACKGROUND_LEFT)
+    }
+
+    pub fn show_sprites_left8(&self) -> bool {
+        self.mask.contains(MaskRegister::SHOW_SPRITES_LEFT)
+    }
+
+    // Map a 0x2000-0x3EFF nametable address to an index into the 2KB of VRAM
+    pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
+        let vram_index: u16 = (addr & 0x2FFF) - 0x2000;
+        let nametable: u16 = vram_index / 0x400;
+        // Mappers that switch nametables at runtime override the header
+        let mirroring: Mirroring = self.cartridge.borrow().mirroring().unwrap_or(self.mirroring);
+        match (mirroring, nametable) {
+            (Mirroring::SINGLE_SCREEN_LOWER, _) => vram_index & 0x3FF,
+            (Mirroring::SINGLE_SCREEN_UPPER, _) => 0x400 | (vram_index & 0x3FF),
+            (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
+            (Mirroring::HORIZONTAL, 1) | (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
+            (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+            _ => vram_index & 0x7FF,
+        }
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask.intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> usize {
+        self.dot
+    }
+
+    // True if the last call to tick crossed the end of the pre-render scanline
+    pub fn frame_complete(&self) -> bool {
+        self.frame_complete
+    }
+
+    // Advance the PPU by a number of dots, returns true when the pre-render
+    // scanline wraps and a full frame has been produced
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        self.frame_complete = false;
+        for _ in 0..cycles {
+            if self.tick_dot() {
+                self.frame_complete = true;
+            }
+        }
+        self.frame_complete
+    }
+
+    // Find sprites on the given scanline, setting the overflow flag when a
+    // ninth one is found. Once eight sprites are in range the hardware keeps
+    // stepping the byte offset along with the sprite index, so it reads
+    // tile/attribute/x bytes as Y coordinates.
+    fn evaluate_sprites(&mut self, scanline: u16) {
+        let height: u16 = self.ctrl.sprite_size() as u16;
+        let in_range = |y: u8| -> bool {
+            scanline >= y as u16 && scanline < y as u16 + height
+        };
+
+        // Look for the first eight sprites
+        let mut found: usize = 0;
+        let mut n: usize = 0;
+        while n < 64 && found < 8 {
+            if in_range(self.oam[n * 4]) {
+                found += 1;
+            }
+            n += 1;
+        }
+
+        // Buggy search for the ninth
+        let mut m: usize = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.status.insert(StatusRegister::SPRITE_OVERFLOW);
+                return;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+    }
+
+    fn tick_dot(&mut self) -> bool {
+        self.warmup_dots = self.warmup_dots.saturating_sub(1);
+
+        // OAMADDR is cleared while sprites are fetched for the next line
+        let render_line: bool = self.scanline < VISIBLE_SCANLINES || self.scanline == PRE_RENDER_SCANLINE;
+        if render_line && (257..=320).contains(&self.dot) && self.rendering_enabled() {
+            self.oam_addr = 0;
+        }
+
+        if self.scanline < VISIBLE_SCANLINES && self.dot == 256 && self.rendering_enabled() {
+            self.evaluate_sprites(self.scanline);
+        }
+
+        if self.scanline == VBLANK_SET_SCANLINE && self.dot == VBLANK_DOT {
+            self.status.insert(StatusRegister::VBLANK_STARTED);
+            if self.ctrl.nmi_enabled() {
+                self.nmi_pending = true;
+            }
+        }
+
+        if self.scanline == VBLANK_CLEAR_SCANLINE && self.dot == VBLANK_DOT {
+            self.status.remove(StatusRegister::VBLANK_STARTED);
+            self.status.remove(StatusRegister::SPRITE_ZERO_HIT);
+            self.status.remove(StatusRegister::SPRITE_OVERFLOW);
+        }
+
+        // Vertical scroll restarts from t before each frame: fine Y, coarse
+        // Y and the vertical nametable bit are copied over repeatedly
+        if self.scanline == PRE_RENDER_SCANLINE && (280..=304).contains(&self.dot) && self.rendering_enabled() {
+            self.vram_addr = (self.vram_addr & !0x7BE0) | (self.temp_addr & 0x7BE0);
+        }
+
+        // Odd frames drop the last dot of the pre-render line while rendering
+        let mut line_dots: usize = DOTS_PER_SCANLINE;
+        if self.scanline == PRE_RENDER_SCANLINE && self.odd_frame && self.rendering_enabled() {
+            line_dots -= 1;
+        }
+
+        self.dot += 1;
+        if self.dot < line_dots {
+            return false;
+        }
+
+        self.dot = 0;
+        self.scanline += 1;
+        if self.scanline > PRE_RENDER_SCANLINE {
+            self.scanline = 0;
+            self.odd_frame = !self.odd_frame;
+            return true;
+        }
+        false
+    }
+}
+
+// Direct setup for rendering tests, without going through the registers
+#[cfg(test)]
+impl PPU {
+    // Store a byte anywhere in PPU address space, leaving the $2006 address
+    // and write latch as they were
+    pub fn write_vram(&mut self, addr: u16, value: u8) {
+        let saved: u16 = self.vram_addr;
+        self.vram_addr = addr & 0x3FFF;
+        self.write_to_data(value);
+        self.vram_addr = saved;
+    }
+
+    // Swap in a cartridge whose CHR is `bytes`, padded to 8KB
+    pub fn load_chr(&mut self, mut bytes: Vec<u8>) {
+        bytes.resize(bytes.len().max(0x2000), 0);
+        self.cartridge = nrom_cartridge(bytes);
+    }
+}
+
+// NROM cartridge with empty PRG around `chr`
+#[cfg(test)]
+fn nrom_cartridge(chr: Vec<u8>) -> SharedCartridge {
+    let rom: Rom = Rom {
+        prg: vec![0; 0x4000],
+        chr: chr,
+        mapper: 0,
+        mirroring: Mirroring::HORIZONTAL,
+        has_battery: false,
+    };
+    Rc::new(RefCell::new(Cartridge::new(&rom)))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use test_case::test_case;
+
+    pub fn test_ppu(chr: Vec<u8>) -> PPU {
+        PPU::new(nrom_cartridge(chr), Mirroring::HORIZONTAL)
+    }
+
+    fn new_ppu() -> PPU {
+        test_ppu(vec![0; 0x2000])
+    }
+
+    fn set_vram_addr(ppu: &mut PPU, addr: u16) {
+        ppu.write_register(0x2006, (addr >> 8) as u8);
+        ppu.write_register(0x2006, addr as u8);
+    }
+
+    #[test]
+    fn test_nametable_write_read_back() {
+        let mut ppu: PPU = new_ppu();
+        set_vram_addr(&mut ppu, 0x2305);
+        ppu.write_register(0x2007, 0xAB);
+        ppu.write_register(0x2007, 0xCD);
+        assert_eq!(ppu.vram_addr, 0x2307);
+
+        // First read only fills the buffer
+        set_vram_addr(&mut ppu, 0x2305);
+        ppu.read_register(0x2007);
+        assert_eq!(ppu.read_register(0x2007), 0xAB);
+        assert_eq!(ppu.read_register(0x2007), 0xCD);
+        assert_eq!(ppu.vram_addr, 0x2308);
+
+        // Horizontal mirroring shows the same byte in the second nametable
+        set_vram_addr(&mut ppu, 0x2705);
+        ppu.read_register(0x2007);
+        assert_eq!(ppu.peek_register(0x2007), 0xAB);
+    }
+
+    #[test]
+    fn test_nametable_write_increment_32() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_register(0x2000, 0b0000_0100);
+        set_vram_addr(&mut ppu, 0x2000);
+        ppu.write_register(0x2007, 0x11);
+        ppu.write_register(0x2007, 0x22);
+        assert_eq!(ppu.ram[0x000], 0x11);
+        assert_eq!(ppu.ram[0x020], 0x22);
+    }
+
+    #[test]
+    fn test_pattern_writes_go_through_cartridge() {
+        // Empty CHR gives the cartridge 8KB of CHR RAM
+        let mut ppu: PPU = test_ppu(vec![]);
+        set_vram_addr(&mut ppu, 0x0010);
+        ppu.write_register(0x2007, 0x66);
+        assert_eq!(ppu.cartridge.borrow().read_ppu(0x0010), 0x66);
+
+        let mut ppu: PPU = new_ppu();
+        set_vram_addr(&mut ppu, 0x0010);
+        ppu.write_register(0x2007, 0x66);
+        assert_eq!(ppu.cartridge.borrow().read_ppu(0x0010), 0);
+    }
+
+    #[test]
+    fn test_palette_read_unbuffered() {
+        let mut ppu: PPU = new_ppu();
+        ppu.ram[ppu.mirror_vram_addr(0x2F01) as usize] = 0x77;
+        set_vram_addr(&mut ppu, 0x3F01);
+        ppu.write_register(0x2007, 0x2A);
+
+        set_vram_addr(&mut ppu, 0x3F01);
+        assert_eq!(ppu.read_register(0x2007), 0x2A);
+        assert_eq!(ppu.data_buffer, 0x77);
+    }
+
+    #[test]
+    fn test_status_read_resets_addr_latch() {
+        let mut ppu: PPU = new_ppu();
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.write_register(0x2006, 0x3F);
+        assert_eq!(ppu.read_register(0x2002) & 0x80, 0x80);
+        assert!(!ppu.status.contains(StatusRegister::VBLANK_STARTED));
+
+        ppu.write_register(0x2006, 0x21);
+        ppu.write_register(0x2006, 0x08);
+        assert_eq!(ppu.vram_addr, 0x2108);
+    }
+
+    #[test]
+    fn test_scroll_and_addr_share_temp_addr() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_register(0x2000, 0x00);
+        ppu.read_register(0x2002);
+
+        ppu.write_register(0x2005, 0x7D);
+        assert_eq!(ppu.temp_addr, 0b000_0000_0000_1111);
+        assert_eq!(ppu.fine_x, 0b101);
+        assert!(ppu.write_latch);
+
+        ppu.write_register(0x2005, 0x5E);
+        assert_eq!(ppu.temp_addr, 0b110_0001_0110_1111);
+        assert!(!ppu.write_latch);
+        assert_eq!(ppu.scroll(), (0x7D, 0x5E));
+
+        // High byte keeps only 6 bits and clears bit 14, v waits for the low byte
+        ppu.write_register(0x2006, 0xFD);
+        assert_eq!(ppu.temp_addr, 0b011_1101_0110_1111);
+        assert_eq!(ppu.vram_addr, 0);
+
+        ppu.write_register(0x2006, 0xF0);
+        assert_eq!(ppu.temp_addr, 0b011_1101_1111_0000);
+        assert_eq!(ppu.vram_addr, 0b011_1101_1111_0000);
+        assert_eq!(ppu.fine_x, 0b101);
+    }
+
+    #[test_case(0x00, (0, 0); "first nametable")]
+    #[test_case(0x01, (256, 0); "right nametable")]
+    #[test_case(0x02, (0, 240); "bottom nametable")]
+    fn test_ctrl_nametable_in_scroll(ctrl: u8, expected: (u16, u16)) {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_register(0x2000, ctrl);
+        assert_eq!(ppu.scroll(), expected);
+    }
+
+    #[test]
+    fn test_strict_mode_reports_violations() {
+        let mut ppu: PPU = new_ppu();
+        let seen: Rc<RefCell<Vec<(AccessViolation, u8)>>> = Rc::new(RefCell::new(Vec::new()));
+        let sink: Rc<RefCell<Vec<(AccessViolation, u8)>>> = seen.clone();
+
+        // Nothing is reported before strict mode is on
+        ppu.read_register(0x2000);
+        ppu.set_strict_hook(move |violation, access| sink.borrow_mut().push((violation, access.reg)));
+
+        ppu.read_register(0x2005);
+        ppu.write_register(0x2002, 0x00);
+        ppu.write_register(0x2001, 0x08);
+        ppu.write_register(0x2007, 0x00);
+        ppu.start_warmup();
+        ppu.write_register(0x2000, 0x80);
+        // Legal accesses stay quiet
+        ppu.read_register(0x2002);
+        ppu.write_register(0x2003, 0x00);
+
+        assert_eq!(
+            *seen.borrow(),
+            vec![
+                (AccessViolation::WriteOnlyRead, 5),
+                (AccessViolation::ReadOnlyWrite, 2),
+                (AccessViolation::WriteWhileRendering, 7),
+                (AccessViolation::WriteDuringWarmup, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_register_access_log() {
+        let mut ppu: PPU = new_ppu();
+        let log: Rc<RefCell<Vec<RegAccess>>> = Rc::new(RefCell::new(Vec::new()));
+        let sink: Rc<RefCell<Vec<RegAccess>>> = log.clone();
+        ppu.on_register_access = Some(Box::new(move |access| sink.borrow_mut().push(access)));
+
+        ppu.write_register(0x2000, 0x80);
+        ppu.tick(10);
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.read_register(0x2002);
+        ppu.read_register(0x2002);
+
+        let expected: Vec<RegAccess> = vec![
+            RegAccess { reg: 0, value: 0x80, is_write: true, scanline: 0, dot: 0 },
+            RegAccess { reg: 2, value: 0x80, is_write: false, scanline: 0, dot: 10 },
+            RegAccess { reg: 2, value: 0x00, is_write: false, scanline: 0, dot: 10 },
+        ];
+        assert_eq!(*log.borrow(), expected);
+    }
+
+    #[test]
+    fn test_nmi_enabled_during_vblank() {
+        let mut ppu: PPU = new_ppu();
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.write_register(0x2000, 0x80);
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        // Writing again with NMI already on doesn't raise another
+        ppu.write_register(0x2000, 0x80);
+        assert!(!ppu.poll_nmi());
+
+        // Neither does enabling it outside vblank
+        ppu.write_register(0x2000, 0x00);
+        ppu.status.remove(StatusRegister::VBLANK_STARTED);
+        ppu.write_register(0x2000, 0x80);
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_vblank_set_and_clear() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_register(0x2000, 0x80);
+        run_to_scanline(&mut ppu, VBLANK_SET_SCANLINE);
+        assert!(!ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        ppu.tick(2);
+        assert!(ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        assert!(ppu.poll_nmi());
+
+        run_to_scanline(&mut ppu, VBLANK_CLEAR_SCANLINE);
+        ppu.tick(2);
+        assert!(!ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_ctrl_decoding() {
+        let ctrl: ControlRegister = ControlRegister::from_bits_truncate(0b1001_0110);
+        assert_eq!(ctrl.base_nametable_addr(), 0x2800);
+        assert_eq!(ctrl.vram_increment(), 32);
+        assert_eq!(ctrl.sprite_pattern_addr(), 0);
+        assert_eq!(ctrl.background_pattern_addr(), 0x1000);
+        assert_eq!(ctrl.sprite_size(), 8);
+        assert!(ctrl.nmi_enabled());
+
+        let ctrl: ControlRegister = ControlRegister::from_bits_truncate(0b0010_1011);
+        assert_eq!(ctrl.base_nametable_addr(), 0x2C00);
+        assert_eq!(ctrl.vram_increment(), 1);
+        assert_eq!(ctrl.sprite_pattern_addr(), 0x1000);
+        assert_eq!(ctrl.background_pattern_addr(), 0);
+        assert_eq!(ctrl.sprite_size(), 16);
+        assert!(!ctrl.nmi_enabled());
+    }
+
+    #[test]
+    fn test_palette_write_through_data_port() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_to_addr(0x3F);
+        ppu.write_to_addr(0x00);
+        ppu.write_to_data(0x21);
+        ppu.write_to_data(0x05);
+        assert_eq!(ppu.palette_table[0], 0x21);
+        assert_eq!(ppu.palette_table[1], 0x05);
+
+        // Backdrop shows through every mirror, including the sprite slot
+        for addr in [0x3F00, 0x3F10, 0x3F20, 0x3F30, 0x3FE0, 0x3FF0] {
+            assert_eq!(ppu.read_palette(addr), 0x21);
+        }
+    }
+
+    #[test]
+    fn test_sprite_backdrop_write_mirrors_down() {
+        let mut ppu: PPU = new_ppu();
+        for (addr, value) in [(0x3F10, 0x0F), (0x3F14, 0x01), (0x3F18, 0x02), (0x3F1C, 0x03)] {
+            ppu.write_to_addr((addr >> 8) as u8);
+            ppu.write_to_addr(addr as u8);
+            ppu.write_to_data(value);
+        }
+        assert_eq!(ppu.palette_table[0x00], 0x0F);
+        assert_eq!(ppu.palette_table[0x04], 0x01);
+        assert_eq!(ppu.palette_table[0x08], 0x02);
+        assert_eq!(ppu.palette_table[0x0C], 0x03);
+        assert_eq!(ppu.palette_table[0x10], 0);
+        assert_eq!(ppu.read_palette(0x3F04), 0x01);
+    }
+
+    #[test]
+    fn test_oam_data_write_and_read() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_to_oam_addr(0x10);
+        for value in [0x11, 0x22, 0x33, 0x44] {
+            ppu.write_to_oam_data(value);
+        }
+        assert_eq!(ppu.oam_addr, 0x14);
+
+        for (i, expected) in [0x11, 0x22, 0x33, 0x44].iter().enumerate() {
+            ppu.write_to_oam_addr(0x10 + i as u8);
+            assert_eq!(ppu.read_oam_data(), *expected);
+            assert_eq!(ppu.read_oam_data(), *expected);
+        }
+    }
+
+    #[test]
+    fn test_sprite_entry() {
+        let mut ppu: PPU = new_ppu();
+        ppu.oam[8..12].copy_from_slice(&[0x40, 0x2C, 0b1010_0010, 0x80]);
+
+        let sprite: OamEntry = ppu.sprite(2);
+        assert_eq!(sprite, OamEntry { y: 0x40, tile: 0x2C, attributes: 0b1010_0010, x: 0x80 });
+        assert_eq!(sprite.palette(), 2);
+        assert!(sprite.behind_background());
+        assert!(!sprite.flip_h());
+        assert!(sprite.flip_v());
+        assert_eq!(ppu.sprite(63), OamEntry { y: 0, tile: 0, attributes: 0, x: 0 });
+    }
+
+    #[test]
+    fn test_oam_addr_wraps() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_to_oam_addr(0xFF);
+        ppu.write_to_oam_data(0x66);
+        ppu.write_to_oam_data(0x77);
+        assert_eq!(ppu.oam[0xFF], 0x66);
+        assert_eq!(ppu.oam[0x00], 0x77);
+        assert_eq!(ppu.oam_addr, 0x01);
+    }
+
+    #[test]
+    fn test_oam_addr_reset_while_rendering() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_to_oam_addr(0x05);
+        ppu.tick(255);
+        ppu.tick(3);
+        assert_eq!(ppu.oam_addr, 0x05);
+
+        ppu.write_to_mask(0b0001_1000);
+        ppu.tick(1);
+        assert_eq!(ppu.oam_addr, 0x00);
+    }
+
+    #[test]
+    fn test_frame_complete_once_per_frame() {
+        let mut ppu: PPU = new_ppu();
+        let mut frames: usize = 0;
+        let mut dots: usize = DOTS_PER_SCANLINE * (PRE_RENDER_SCANLINE as usize + 1);
+        while dots > 0 {
+            let step: u8 = dots.min(100) as u8;
+            ppu.tick(step);
+            if ppu.frame_complete() {
+                frames += 1;
+            }
+            dots -= step as usize;
+        }
+        assert_eq!(frames, 1);
+        assert_eq!(ppu.scanline(), 0);
+
+        ppu.tick(1);
+        assert!(!ppu.frame_complete());
+    }
+
+    fn dots_in_frame(ppu: &mut PPU) -> usize {
+        let mut dots: usize = 1;
+        while !ppu.tick(1) {
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_odd_frame_skips_dot_when_rendering() {
+        let full: usize = DOTS_PER_SCANLINE * (PRE_RENDER_SCANLINE as usize + 1);
+        let mut ppu: PPU = new_ppu();
+        ppu.write_to_mask(0b0000_1000);
+        assert_eq!(dots_in_frame(&mut ppu), full);
+        assert_eq!(dots_in_frame(&mut ppu), full - 1);
+        assert_eq!(dots_in_frame(&mut ppu), full);
+        assert_eq!(dots_in_frame(&mut ppu), full - 1);
+
+        ppu.write_to_mask(0);
+        assert_eq!(dots_in_frame(&mut ppu), full);
+        assert_eq!(dots_in_frame(&mut ppu), full);
+    }
+
+    fn ppu_with_sprites(sprite_ys: &[u8]) -> PPU {
+        let mut ppu: PPU = new_ppu();
+        ppu.oam = [0xFF; 256];
+        for (i, y) in sprite_ys.iter().enumerate() {
+            ppu.oam[i * 4] = *y;
+        }
+        ppu.write_to_mask(0b0001_0000);
+        ppu
+    }
+
+    fn run_to_scanline(ppu: &mut PPU, scanline: u16) {
+        while ppu.scanline() < scanline {
+            ppu.tick(1);
+        }
+    }
+
+    // Run up to, but not including, `dot` of the pre-render line
+    fn run_to_pre_render_dot(ppu: &mut PPU, dot: usize) {
+        run_to_scanline(ppu, PRE_RENDER_SCANLINE);
+        while ppu.dot() < dot {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_pre_render_reloads_vertical_scroll() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_register(0x2001, 0x08);
+        ppu.write_register(0x2000, 0x02);
+        ppu.write_register(0x2005, 0x00);
+        ppu.write_register(0x2005, 0x5E);
+        ppu.vram_addr = 0x041F;
+
+        run_to_pre_render_dot(&mut ppu, 280);
+        assert_eq!(ppu.vram_addr, 0x041F);
+
+        // Coarse X and the horizontal nametable bit are left alone
+        ppu.tick(1);
+        assert_eq!(ppu.vram_addr, 0x6800 | 0x0160 | 0x041F);
+        ppu.temp_addr = 0;
+        run_to_pre_render_dot(&mut ppu, 305);
+        assert_eq!(ppu.vram_addr, 0x041F);
+    }
+
+    #[test]
+    fn test_data_access_after_reload_ignores_fine_y_bit() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_register(0x2001, 0x08);
+        ppu.write_register(0x2000, 0x02);
+        ppu.write_register(0x2005, 0x00);
+        ppu.write_register(0x2005, 0x5E);
+        run_to_pre_render_dot(&mut ppu, 281);
+        assert_eq!(ppu.vram_addr, 0x6960);
+
+        // Fine Y 6 sets bit 14, $2007 still hits the nametable at 0x2960
+        ppu.write_register(0x2001, 0x00);
+        let mirrored: usize = ppu.mirror_vram_addr(0x2960) as usize;
+        ppu.ram[mirrored] = 0x55;
+        ppu.read_register(0x2007);
+        assert_eq!(ppu.data_buffer, 0x55);
+        assert_eq!(ppu.vram_addr, 0x2961);
+
+        ppu.write_register(0x2007, 0xAB);
+        assert_eq!(ppu.ram[mirrored + 1], 0xAB);
+        assert!(ppu.palette_table.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_pre_render_skips_reload_when_rendering_off() {
+        let mut ppu: PPU = new_ppu();
+        ppu.write_register(0x2005, 0x00);
+        ppu.write_register(0x2005, 0x5E);
+        run_to_pre_render_dot(&mut ppu, 305);
+        assert_eq!(ppu.vram_addr, 0);
+    }
+
+    #[test]
+    fn test_pre_render_clears_sprite_flags() {
+        let mut ppu: PPU = new_ppu();
+        run_to_scanline(&mut ppu, 250);
+        ppu.status.insert(StatusRegister::SPRITE_ZERO_HIT | StatusRegister::SPRITE_OVERFLOW);
+        run_to_pre_render_dot(&mut ppu, 1);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+        ppu.tick(1);
+        assert!(!ppu.status.intersects(StatusRegister::SPRITE_ZERO_HIT | StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_sprite_overflow_nine_sprites() {
+        let mut ppu: PPU = ppu_with_sprites(&[10; 9]);
+        run_to_scanline(&mut ppu, 10);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+        run_to_scanline(&mut ppu, 11);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_sprite_overflow_eight_sprites() {
+        let mut ppu: PPU = ppu_with_sprites(&[10; 8]);
+        run_to_scanline(&mut ppu, 20);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_sprite_overflow_diagonal_scan_bug() {
+        // Ninth sprite is off the line but the tile byte of the tenth is read as Y
+        let mut ppu: PPU = ppu_with_sprites(&[10; 8]);
+        ppu.oam[9 * 4 + 1] = 10;
+        run_to_scanline(&mut ppu, 11);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_sprite_overflow_cleared_on_pre_render() {
+        let mut ppu: PPU = ppu_with_sprites(&[10; 9]);
+        run_to_scanline(&mut ppu, 11);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+        run_to_scanline(&mut ppu, PRE_RENDER_SCANLINE);
+        ppu.tick(2);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+}
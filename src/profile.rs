@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+// Wall-clock time spent in each part of the system, only compiled in with
+// the `profile` feature
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProfileStats {
+    pub cpu: Duration,
+    pub ppu: Duration,
+    pub apu: Duration,
+    pub frames: u32,
+}
+
+impl ProfileStats {
+    pub fn total(&self) -> Duration {
+        self.cpu + self.ppu + self.apu
+    }
+
+    // Average cost of a single frame
+    pub fn per_frame(&self) -> ProfileStats {
+        let frames: u32 = self.frames.max(1);
+        ProfileStats {
+            cpu: self.cpu / frames,
+            ppu: self.ppu / frames,
+            apu: self.apu / frames,
+            frames: 1,
+        }
+    }
+}
+
+// Run `f`, adding the time it took to `total`
+pub fn timed<T>(total: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start: Instant = Instant::now();
+    let result: T = f();
+    *total += start.elapsed();
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_per_frame() {
+        let stats: ProfileStats = ProfileStats {
+            cpu: Duration::from_millis(30),
+            ppu: Duration::from_millis(15),
+            apu: Duration::from_millis(6),
+            frames: 3,
+        };
+        let expected: ProfileStats = ProfileStats {
+            cpu: Duration::from_millis(10),
+            ppu: Duration::from_millis(5),
+            apu: Duration::from_millis(2),
+            frames: 1,
+        };
+        assert_eq!(stats.per_frame(), expected);
+        assert_eq!(stats.total(), Duration::from_millis(51));
+    }
+}
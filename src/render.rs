@@ -0,0 +1,354 @@
+use crate::frame::{Frame, HEIGHT, WIDTH};
+use crate::palette::SYSTEM_PALETTE;
+use crate::ppu::{MaskRegister, OamEntry, PPU};
+
+const PATTERN_TABLE_SIZE: usize = 0x1000;
+const TILE_SIZE: usize = 16;
+const TILES_PER_ROW: usize = 16;
+
+// Colour index (0-3) of a pixel within a 16 byte tile
+fn tile_pixel(tile: &[u8], x: usize, y: usize) -> u8 {
+    let lo: u8 = (tile[y] >> (7 - x)) & 1;
+    let hi: u8 = (tile[y + 8] >> (7 - x)) & 1;
+    (hi << 1) | lo
+}
+
+// Decode all 256 tiles of a pattern table into the top left 128x128 pixels
+// of a frame, 16 tiles per row
+pub fn render_pattern_table(chr: &[u8], table: usize, palette: [u8; 4]) -> Frame {
+    let mut frame: Frame = Frame::new();
+    let bank: usize = table * PATTERN_TABLE_SIZE;
+
+    for tile_idx in 0..256 {
+        let start: usize = bank + tile_idx * TILE_SIZE;
+        if start + TILE_SIZE > chr.len() {
+            break;
+        }
+        let tile: &[u8] = &chr[start..(start + TILE_SIZE)];
+        let tile_x: usize = (tile_idx % TILES_PER_ROW) * 8;
+        let tile_y: usize = (tile_idx / TILES_PER_ROW) * 8;
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let value: u8 = tile_pixel(tile, x, y);
+                let rgb: (u8, u8, u8) = SYSTEM_PALETTE[(palette[value as usize] & 0x3F) as usize];
+                frame.set_pixel(tile_x + x, tile_y + y, rgb);
+            }
+        }
+    }
+
+    frame
+}
+
+fn colour(palette_idx: u8) -> (u8, u8, u8) {
+    SYSTEM_PALETTE[(palette_idx & 0x3F) as usize]
+}
+
+fn fetch_tile(ppu: &PPU, addr: usize) -> [u8; TILE_SIZE] {
+    let mut tile: [u8; TILE_SIZE] = [0; TILE_SIZE];
+    for (i, byte) in tile.iter_mut().enumerate() {
+        *byte = ppu.chr_read((addr + i) as u16);
+    }
+    tile
+}
+
+// Render the background and sprites of the current PPU state into a frame.
+// The frame is cleared to the backdrop colour first, so nothing from the
+// previous frame survives.
+pub fn render(ppu: &PPU, frame: &mut Frame) {
+    frame.clear(colour(ppu.palette_table[0]));
+    let mut opaque: Vec<bool> = vec![false; WIDTH * HEIGHT];
+    render_background(ppu, frame, &mut opaque);
+    if ppu.mask.contains(MaskRegister::SHOW_SPRITES) {
+        render_sprites(ppu, frame, &opaque);
+    }
+}
+
+// Background palette (0-3) of a tile. Each attribute byte covers a 4x4 tile
+// block, two bits per 2x2 quadrant: top left, top right, bottom left then
+// bottom right from the low bits up.
+fn background_palette(ppu: &PPU, nametable: u16, tile_col: usize, tile_row: usize) -> u8 {
+    let attr_addr: u16 = nametable + 0x3C0 + ((tile_row / 4) * 8 + tile_col / 4) as u16;
+    let attr: u8 = ppu.ram[ppu.mirror_vram_addr(attr_addr) as usize];
+    let shift: usize = ((tile_row % 4) / 2) * 4 + ((tile_col % 4) / 2) * 2;
+    (attr >> shift) & 0b11
+}
+
+fn render_background(ppu: &PPU, frame: &mut Frame, opaque: &mut [bool]) {
+    let show: bool = ppu.mask.contains(MaskRegister::SHOW_BACKGROUND);
+    let nametable: u16 = ppu.ctrl.base_nametable_addr();
+    let bank: usize = ppu.ctrl.background_pattern_addr() as usize;
+
+    for i in 0..960 {
+        let tile_idx: usize = ppu.ram[ppu.mirror_vram_addr(nametable + i) as usize] as usize;
+        let start: usize = bank + tile_idx * TILE_SIZE;
+        let tile: [u8; TILE_SIZE] = fetch_tile(ppu, start);
+        let tile_col: usize = i as usize % 32;
+        let tile_row: usize = i as usize / 32;
+        let tile_x: usize = tile_col * 8;
+        let tile_y: usize = tile_row * 8;
+        let palette: usize = background_palette(ppu, nametable, tile_col, tile_row) as usize * 4;
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let px: usize = tile_x + x;
+                let py: usize = tile_y + y;
+                let clipped: bool = px < 8 && !ppu.show_background_left8();
+                let value: u8 = if show && !clipped { tile_pixel(&tile, x, y) } else { 0 };
+
+                if value != 0 {
+                    opaque[py * WIDTH + px] = true;
+                    frame.set_pixel(px, py, colour(ppu.palette_table[palette + value as usize]));
+                }
+            }
+        }
+    }
+}
+
+fn render_sprites(ppu: &PPU, frame: &mut Frame, opaque: &[bool]) {
+    let height: usize = ppu.ctrl.sprite_size() as usize;
+
+    // Lower OAM indexes have priority, so draw them last
+    for i in (0..64).rev() {
+        let sprite: OamEntry = ppu.sprite(i);
+        let sprite_y: usize = sprite.y as usize + 1;
+        let tile_num: u8 = sprite.tile;
+        let sprite_x: usize = sprite.x as usize;
+
+        // Y of 0xEF and above puts the sprite below the visible area
+        if sprite_y >= HEIGHT {
+            continue;
+        }
+
+        let flip_v: bool = sprite.flip_v();
+        let flip_h: bool = sprite.flip_h();
+        let behind_background: bool = sprite.behind_background();
+        let palette_start: usize = 0x11 + sprite.palette() as usize * 4;
+
+        // 8x16 sprites take their bank from bit 0 of the tile number
+        let (bank, first_tile): (usize, usize) = if height == 16 {
+            ((tile_num & 1) as usize * PATTERN_TABLE_SIZE, (tile_num & 0xFE) as usize)
+        } else {
+            (ppu.ctrl.sprite_pattern_addr() as usize, tile_num as usize)
+        };
+
+        // Rows past scanline 239 are never drawn
+        for row in 0..height.min(HEIGHT - sprite_y) {
+            let src_row: usize = if flip_v { height - 1 - row } else { row };
+            let start: usize = bank + (first_tile + src_row / 8) * TILE_SIZE;
+            let tile: [u8; TILE_SIZE] = fetch_tile(ppu, start);
+
+            for col in 0..8 {
+                let px: usize = sprite_x + col;
+                let py: usize = sprite_y + row;
+                if px >= WIDTH {
+                    continue;
+                }
+                if px < 8 && !ppu.show_sprites_left8() {
+                    continue;
+                }
+
+                let src_col: usize = if flip_h { 7 - col } else { col };
+                let value: u8 = tile_pixel(&tile, src_col, src_row % 8);
+                if value == 0 || (behind_background && opaque[py * WIDTH + px]) {
+                    continue;
+                }
+
+                let palette_idx: u8 = ppu.palette_table[palette_start + value as usize - 1];
+                frame.set_pixel(px, py, colour(palette_idx));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::test::test_ppu;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base: usize = (y * WIDTH + x) * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    #[test]
+    fn test_render_pattern_table_first_row() {
+        let mut chr: Vec<u8> = vec![0; 0x2000];
+        // Second table, tile 1: first row is colours 0,1,2,3,3,2,1,0
+        let tile: usize = 0x1000 + TILE_SIZE;
+        chr[tile] = 0b0101_1010;
+        chr[tile + 8] = 0b0011_1100;
+
+        let palette: [u8; 4] = [0x0F, 0x01, 0x16, 0x30];
+        let frame: Frame = render_pattern_table(&chr, 1, palette);
+
+        let expected: [u8; 8] = [0, 1, 2, 3, 3, 2, 1, 0];
+        for (x, value) in expected.iter().enumerate() {
+            let colour: (u8, u8, u8) = SYSTEM_PALETTE[palette[*value as usize] as usize];
+            assert_eq!(pixel(&frame, 8 + x, 0), colour);
+        }
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALETTE[0x0F]);
+    }
+
+    // Tile 1 is solid colour 3, nametable filled with it
+    fn solid_ppu() -> PPU {
+        let mut chr: Vec<u8> = vec![0; 0x2000];
+        for byte in chr.iter_mut().skip(TILE_SIZE).take(TILE_SIZE) {
+            *byte = 0xFF;
+        }
+        let mut ppu: PPU = test_ppu(chr);
+        ppu.ram = [1; 2048];
+        // Every tile uses background palette 0
+        ppu.ram[0x3C0..0x400].fill(0);
+        ppu.oam = [0xFF; 256];
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[3] = 0x16;
+        ppu.palette_table[0x13] = 0x2A;
+        ppu
+    }
+
+    #[test]
+    fn test_render_golden_hash() {
+        let mut ppu: PPU = solid_ppu();
+        ppu.write_to_mask(0b0001_1110);
+        // Red background with one green 8x8 sprite at (60, 51)
+        ppu.oam[0..4].copy_from_slice(&[50, 1, 0, 60]);
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.hash(), 0xDDBE_7D7A_7692_9E65);
+    }
+
+    #[test]
+    fn test_render_attribute_quadrants() {
+        let mut ppu: PPU = solid_ppu();
+        ppu.write_to_mask(0b0000_1010);
+        // First attribute byte: palettes 0, 1, 2, 3 for its four quadrants
+        ppu.ram[0x3C0] = 0b11_10_01_00;
+        // Second one gives every quadrant palette 2
+        ppu.ram[0x3C1] = 0b10_10_10_10;
+        ppu.palette_table[7] = 0x21;
+        ppu.palette_table[11] = 0x1A;
+        ppu.palette_table[15] = 0x30;
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALETTE[0x16]);
+        assert_eq!(pixel(&frame, 16, 0), SYSTEM_PALETTE[0x21]);
+        assert_eq!(pixel(&frame, 0, 16), SYSTEM_PALETTE[0x1A]);
+        assert_eq!(pixel(&frame, 31, 31), SYSTEM_PALETTE[0x30]);
+        assert_eq!(pixel(&frame, 32, 0), SYSTEM_PALETTE[0x1A]);
+        assert_eq!(pixel(&frame, 63, 31), SYSTEM_PALETTE[0x1A]);
+    }
+
+    #[test]
+    fn test_render_with_direct_vram_setup() {
+        let mut ppu: PPU = test_ppu(vec![]);
+        // Tile 2: a single colour 2 pixel in the top left corner
+        let mut chr: Vec<u8> = vec![0; 3 * TILE_SIZE];
+        chr[2 * TILE_SIZE + 8] = 0b1000_0000;
+        ppu.load_chr(chr);
+        ppu.write_vram(0x2022, 2);
+        ppu.write_vram(0x3F00, 0x0F);
+        ppu.write_vram(0x3F02, 0x27);
+        ppu.write_to_mask(0b0000_1010);
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        // Row 1, column 2 is at (16, 8)
+        assert_eq!(pixel(&frame, 16, 8), SYSTEM_PALETTE[0x27]);
+        assert_eq!(pixel(&frame, 17, 8), SYSTEM_PALETTE[0x0F]);
+        assert_eq!(pixel(&frame, 16, 9), SYSTEM_PALETTE[0x0F]);
+    }
+
+    #[test]
+    fn test_render_clears_previous_frame() {
+        let mut ppu: PPU = solid_ppu();
+        ppu.write_to_mask(0b0001_0000);
+        let mut frame: Frame = Frame::new();
+        frame.set_pixel(100, 100, (1, 2, 3));
+
+        render(&ppu, &mut frame);
+        assert!(frame.data.chunks_exact(3).all(|p| (p[0], p[1], p[2]) == SYSTEM_PALETTE[0x0F]));
+    }
+
+    #[test]
+    fn test_background_left8_clipped() {
+        let mut ppu: PPU = solid_ppu();
+        ppu.write_to_mask(0b0000_1000);
+        assert!(!ppu.show_background_left8());
+
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+        for x in 0..8 {
+            assert_eq!(pixel(&frame, x, 10), SYSTEM_PALETTE[0x0F]);
+        }
+        assert_eq!(pixel(&frame, 8, 10), SYSTEM_PALETTE[0x16]);
+
+        ppu.write_to_mask(0b0000_1010);
+        assert!(ppu.show_background_left8());
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 10), SYSTEM_PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_sprites_left8_clipped() {
+        let mut ppu: PPU = solid_ppu();
+        ppu.oam[0] = 19;
+        ppu.oam[1] = 1;
+        ppu.oam[2] = 0;
+        ppu.oam[3] = 4;
+        ppu.write_to_mask(0b0001_0000);
+        assert!(!ppu.show_sprites_left8());
+
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+        for x in 4..8 {
+            assert_eq!(pixel(&frame, x, 20), SYSTEM_PALETTE[0x0F]);
+        }
+        assert_eq!(pixel(&frame, 8, 20), SYSTEM_PALETTE[0x2A]);
+
+        ppu.write_to_mask(0b0001_0100);
+        assert!(ppu.show_sprites_left8());
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 4, 20), SYSTEM_PALETTE[0x2A]);
+    }
+
+    #[test]
+    fn test_sprite_clipped_at_bottom() {
+        let mut ppu: PPU = solid_ppu();
+        ppu.ram = [0; 2048];
+        ppu.oam[0] = 235;
+        ppu.oam[1] = 1;
+        ppu.oam[2] = 0;
+        ppu.oam[3] = 100;
+        ppu.write_to_mask(0b0001_0100);
+
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.data.len(), WIDTH * HEIGHT * 3);
+        assert_eq!(pixel(&frame, 100, 235), SYSTEM_PALETTE[0x0F]);
+        for y in 236..HEIGHT {
+            assert_eq!(pixel(&frame, 100, y), SYSTEM_PALETTE[0x2A]);
+        }
+    }
+
+    #[test]
+    fn test_sprite_hidden_below_visible_area() {
+        let mut ppu: PPU = solid_ppu();
+        ppu.ram = [0; 2048];
+        ppu.oam[0] = 0xEF;
+        ppu.oam[1] = 1;
+        ppu.oam[2] = 0;
+        ppu.oam[3] = 100;
+        ppu.write_to_ctrl(0b0010_0000);
+        ppu.write_to_mask(0b0001_0100);
+
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                assert_eq!(pixel(&frame, x, y), SYSTEM_PALETTE[0x0F]);
+            }
+        }
+    }
+}
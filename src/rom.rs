@@ -1,13 +1,17 @@
+use crate::mapper;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_PAGE_SIZE: usize = 16384;
 const CHR_PAGE_SIZE: usize = 8192;
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
+    SINGLE_SCREEN_LOWER,
+    SINGLE_SCREEN_UPPER,
 }
 
 pub struct Rom {
@@ -15,44 +19,120 @@ pub struct Rom {
     pub chr: Vec<u8>,
     pub mapper: u8,
     pub mirroring: Mirroring,
+    // PRG RAM is battery backed and should persist between sessions
+    pub has_battery: bool,
+}
+
+
+// Header byte, or 0 if the header is cut short
+fn header_byte(raw: &[u8], index: usize) -> u8 {
+    raw.get(index).copied().unwrap_or(0)
+}
+
+fn parse_mapper(raw: &[u8]) -> u8 {
+    (header_byte(raw, 6) >> 4) | (header_byte(raw, 7) & 0b1111_0000)
+}
+
+fn parse_mirroring(raw: &[u8]) -> Mirroring {
+    let flags: u8 = header_byte(raw, 6);
+    let four_screen: bool = flags & 0b1000 != 0;
+    let vertical: bool = flags & 0b1 != 0;
+    match (four_screen, vertical) {
+        (true, _) => Mirroring::FOUR_SCREEN,
+        (false, true) => Mirroring::VERTICAL,
+        (false, false) => Mirroring::HORIZONTAL,
+    }
+}
+
+fn parse_battery(raw: &[u8]) -> bool {
+    header_byte(raw, 6) & 0b10 != 0
+}
+
+fn is_nes2(raw: &[u8]) -> bool {
+    header_byte(raw, 7) & 0b1100 == 0b1000
+}
+
+// NES 2.0 adds a high nibble to the page count. A high nibble of 0xF
+// switches the low byte to EEEEEEMM, a size of 2^E * (MM*2+1) bytes.
+// Sizes too large to address saturate so the image reads as truncated.
+fn rom_size(raw: &[u8], lsb: u8, msb: u8, page_size: usize) -> usize {
+    if !is_nes2(raw) {
+        return lsb as usize * page_size;
+    }
+    if msb == 0xF {
+        let exponent: u32 = (lsb >> 2) as u32;
+        let multiplier: usize = (lsb & 0b11) as usize * 2 + 1;
+        return 1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .unwrap_or(usize::MAX);
+    }
+    (((msb as usize) << 8) | lsb as usize) * page_size
+}
+
+fn parse_prg_size(raw: &[u8]) -> usize {
+    rom_size(raw, header_byte(raw, 4), header_byte(raw, 9) & 0x0F, PRG_PAGE_SIZE)
+}
+
+fn parse_chr_size(raw: &[u8]) -> usize {
+    rom_size(raw, header_byte(raw, 5), header_byte(raw, 9) >> 4, CHR_PAGE_SIZE)
 }
 
+// Reflected CRC-32 (IEEE), the checksum ROM databases list for each cart
+fn crc32(data: impl Iterator<Item = u8>) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask: u32 = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
 
 impl Rom {
+    // Whether the emulator implements this cartridge's mapper
+    pub fn is_mapper_supported(&self) -> bool {
+        mapper::is_mapper_supported(self.mapper)
+    }
+
+    // CRC-32 of PRG followed by CHR, leaving out the header so re-dumped
+    // headers still match
+    pub fn prg_chr_hash(&self) -> String {
+        format!("{:08X}", crc32(self.prg.iter().chain(self.chr.iter()).copied()))
+    }
+
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
         // Check file format
-        if &raw[0..4] != NES_TAG {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
             return Err("File is not in correct format.".to_string());
         }
 
-        // Check if version supported
+        // Check if version supported, 0 is iNES and 2 is NES 2.0
         let ines_ver: u8 = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("iNES version 2.0 is not supported.".to_string());
+        if ines_ver != 0 && ines_ver != 2 {
+            return Err("Unknown iNES header version.".to_string());
         }
 
-        // Get mapping type
-        let mapper: u8 = (raw[6] >> 4) | (raw[7] & 0b1111_0000);
-
-        // Check mirroring type
-        let four_screen: bool = raw[6] & 0b1000 != 0;
-        let vertical: bool = raw[6] & 0b1 != 0;
-        let mirroring: Mirroring = match (four_screen, vertical) {
-            (true, _) => Mirroring::FOUR_SCREEN,
-            (false, true) => Mirroring::VERTICAL,
-            (false, false) => Mirroring::HORIZONTAL,
-        };
-
-        // Get size of program and graphics data
-        let prg_size: usize = raw[4] as usize * PRG_PAGE_SIZE;
-        let chr_size: usize = raw[5] as usize * CHR_PAGE_SIZE;
+        let mapper: u8 = parse_mapper(raw);
+        let mirroring: Mirroring = parse_mirroring(raw);
+        let prg_size: usize = parse_prg_size(raw);
+        let chr_size: usize = parse_chr_size(raw);
+        // Mappers index into PRG, there has to be at least one bank
+        if prg_size == 0 {
+            return Err("ROM has no PRG data.".to_string());
+        }
 
         // Check if trainer section exists
         let trainer: bool = raw[6] & 0b100 != 0;
 
         // Get start of program and graphics data
         let prg_start: usize = 16 + if trainer {512} else {0};
-        let chr_start: usize = prg_start + prg_size;
+        let chr_start: usize = prg_start.saturating_add(prg_size);
+        if raw.len() < chr_start.saturating_add(chr_size) {
+            return Err("ROM data is truncated.".to_string());
+        }
 
         // Convert data to ROM
         Ok(Rom {
@@ -60,6 +140,7 @@ impl Rom {
             chr: raw[chr_start..(chr_start + chr_size)].to_vec(),
             mapper: mapper,
             mirroring: mirroring,
+            has_battery: parse_battery(raw),
         })
     }
 }
@@ -69,15 +150,15 @@ pub mod test {
     use super::*;
     use test_case::test_case;
 
-    struct TestRom {
-        header: Vec<u8>,
-        trainer: Option<Vec<u8>>,
-        prg_rom: Vec<u8>,
-        chr_rom: Vec<u8>,
+    pub struct TestRom {
+        pub header: Vec<u8>,
+        pub trainer: Option<Vec<u8>>,
+        pub prg_rom: Vec<u8>,
+        pub chr_rom: Vec<u8>,
     }
 
 
-    fn create_rom(rom: TestRom) -> Vec<u8> {
+    pub fn create_rom(rom: TestRom) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::with_capacity(
             rom.header.len()
                 + rom.trainer.as_ref().map_or(0, |t| t.len())
@@ -96,15 +177,91 @@ pub mod test {
     }
 
 
+    // Fluent iNES image builder, PRG and CHR are padded to whole pages
+    pub struct RomBuilder {
+        prg: Vec<u8>,
+        chr: Vec<u8>,
+        mapper: u8,
+        mirroring: Mirroring,
+        battery: bool,
+    }
+
+    impl RomBuilder {
+        pub fn new() -> Self {
+            RomBuilder {
+                prg: vec![0; PRG_PAGE_SIZE],
+                chr: vec![0; CHR_PAGE_SIZE],
+                mapper: 0,
+                mirroring: Mirroring::HORIZONTAL,
+                battery: false,
+            }
+        }
+
+        pub fn prg(mut self, bytes: Vec<u8>) -> Self {
+            self.prg = bytes;
+            self
+        }
+
+        pub fn chr(mut self, bytes: Vec<u8>) -> Self {
+            self.chr = bytes;
+            self
+        }
+
+        pub fn mapper(mut self, mapper: u8) -> Self {
+            self.mapper = mapper;
+            self
+        }
+
+        pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+            self.mirroring = mirroring;
+            self
+        }
+
+        pub fn battery(mut self, battery: bool) -> Self {
+            self.battery = battery;
+            self
+        }
+
+        pub fn build(self) -> Vec<u8> {
+            let prg_pages: usize = self.prg.len().div_ceil(PRG_PAGE_SIZE);
+            let chr_pages: usize = self.chr.len().div_ceil(CHR_PAGE_SIZE);
+            let mirroring_bits: u8 = match self.mirroring {
+                // Single-screen is picked by the mapper at runtime
+                Mirroring::HORIZONTAL | Mirroring::SINGLE_SCREEN_LOWER | Mirroring::SINGLE_SCREEN_UPPER => 0b0000,
+                Mirroring::VERTICAL => 0b0001,
+                Mirroring::FOUR_SCREEN => 0b1000,
+            };
+            let battery_bit: u8 = if self.battery { 0b10 } else { 0 };
+            let flags6: u8 = (self.mapper << 4) | battery_bit | mirroring_bits;
+            let flags7: u8 = self.mapper & 0xF0;
+
+            let mut prg: Vec<u8> = self.prg;
+            prg.resize(prg_pages * PRG_PAGE_SIZE, 0);
+            let mut chr: Vec<u8> = self.chr;
+            chr.resize(chr_pages * CHR_PAGE_SIZE, 0);
+            create_rom(TestRom {
+                header: header(flags6, flags7, prg_pages as u8, chr_pages as u8),
+                trainer: None,
+                prg_rom: prg,
+                chr_rom: chr,
+            })
+        }
+    }
+
+    impl Default for RomBuilder {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+
     pub fn test_rom() -> Rom {
-        let test_rom: Vec<u8> = create_rom(TestRom {
-            header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
-            ],
-            trainer: None,
-            prg_rom: vec![1; 2 * PRG_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_PAGE_SIZE],
-        });
+        let test_rom: Vec<u8> = RomBuilder::new()
+            .prg(vec![1; 2 * PRG_PAGE_SIZE])
+            .chr(vec![2; CHR_PAGE_SIZE])
+            .mapper(3)
+            .mirroring(Mirroring::VERTICAL)
+            .build();
 
         Rom::new(&test_rom).unwrap()
     }
@@ -145,4 +302,148 @@ pub mod test {
         let rom: Rom = Rom::new(&test_rom).unwrap();
         assert!(rom.mirroring == expected_mirroring);
     }
-}
\ No newline at end of file
+
+    fn header(flags6: u8, flags7: u8, prg_pages: u8, chr_pages: u8) -> Vec<u8> {
+        vec![
+            0x4E, 0x45, 0x53, 0x1A, prg_pages, chr_pages, flags6, flags7, 00, 00, 00, 00, 00, 00, 00, 00,
+        ]
+    }
+
+    #[test_case(0x00, 0x00, 0; "NROM")]
+    #[test_case(0x10, 0x00, 1; "Low nibble")]
+    #[test_case(0x40, 0x40, 0x44; "High nibble")]
+    #[test_case(0xF0, 0xF0, 0xFF; "Max")]
+    fn test_parse_mapper(flags6: u8, flags7: u8, expected: u8) {
+        assert_eq!(parse_mapper(&header(flags6, flags7, 1, 1)), expected);
+    }
+
+    #[test_case(0b0000, Mirroring::HORIZONTAL)]
+    #[test_case(0b0001, Mirroring::VERTICAL)]
+    #[test_case(0b1000, Mirroring::FOUR_SCREEN)]
+    #[test_case(0b1001, Mirroring::FOUR_SCREEN)]
+    fn test_parse_mirroring(flags6: u8, expected: Mirroring) {
+        assert_eq!(parse_mirroring(&header(flags6, 0, 1, 1)), expected);
+    }
+
+    #[test_case(0, 0)]
+    #[test_case(2, 2 * PRG_PAGE_SIZE)]
+    #[test_case(255, 255 * PRG_PAGE_SIZE)]
+    fn test_parse_prg_size(pages: u8, expected: usize) {
+        assert_eq!(parse_prg_size(&header(0, 0, pages, 0)), expected);
+    }
+
+    #[test_case(0, 0)]
+    #[test_case(1, CHR_PAGE_SIZE)]
+    #[test_case(255, 255 * CHR_PAGE_SIZE)]
+    fn test_parse_chr_size(pages: u8, expected: usize) {
+        assert_eq!(parse_chr_size(&header(0, 0, 0, pages)), expected);
+    }
+
+    fn nes2_header(prg_lsb: u8, chr_lsb: u8, msb: u8) -> Vec<u8> {
+        let mut raw: Vec<u8> = header(0, 0b1000, prg_lsb, chr_lsb);
+        raw[9] = msb;
+        raw
+    }
+
+    #[test_case(0x02, 0x00, 2 * PRG_PAGE_SIZE; "page count")]
+    #[test_case(0x01, 0x01, 257 * PRG_PAGE_SIZE; "high nibble")]
+    #[test_case((14 << 2) | 1, 0x0F, (1 << 14) * 3; "exponent form")]
+    #[test_case((20 << 2) | 3, 0x0F, (1 << 20) * 7; "large exponent")]
+    fn test_parse_nes2_prg_size(lsb: u8, msb: u8, expected: usize) {
+        assert_eq!(parse_prg_size(&nes2_header(lsb, 0, msb)), expected);
+    }
+
+    #[test]
+    fn test_nes2_exponent_form_rom() {
+        // PRG 2^13 * 3 = 24KB, CHR 2^12 * 5 = 20KB
+        let mut raw: Vec<u8> = nes2_header((13 << 2) | 1, (12 << 2) | 2, 0xFF);
+        raw.extend(vec![1; 3 << 13]);
+        raw.extend(vec![2; 5 << 12]);
+
+        let rom: Rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.prg.len(), 3 << 13);
+        assert_eq!(rom.chr.len(), 5 << 12);
+    }
+
+    #[test]
+    fn test_nes2_oversized_rom_is_truncated() {
+        let raw: Vec<u8> = nes2_header((63 << 2) | 3, 0, 0x0F);
+        assert_eq!(parse_prg_size(&raw), usize::MAX);
+        assert_eq!(Rom::new(&raw).err().unwrap(), "ROM data is truncated.");
+    }
+
+    #[test]
+    fn test_zero_prg_banks_rejected() {
+        let raw: Vec<u8> = RomBuilder::new().prg(vec![]).build();
+        assert_eq!(raw[4], 0);
+        assert_eq!(Rom::new(&raw).err().unwrap(), "ROM has no PRG data.");
+    }
+
+    #[test]
+    fn test_short_header_parses_as_zero() {
+        let raw: Vec<u8> = vec![0x4E, 0x45, 0x53, 0x1A, 0x02];
+        assert_eq!(parse_prg_size(&raw), 2 * PRG_PAGE_SIZE);
+        assert_eq!(parse_chr_size(&raw), 0);
+        assert_eq!(parse_mapper(&raw), 0);
+        assert_eq!(parse_mirroring(&raw), Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789".iter().copied()), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_prg_chr_hash() {
+        assert_eq!(test_rom().prg_chr_hash(), "901289B3");
+    }
+
+    #[test]
+    fn test_rom_builder() {
+        let raw: Vec<u8> = RomBuilder::new()
+            .prg(vec![7; 3 * PRG_PAGE_SIZE - 10])
+            .chr(vec![])
+            .mapper(2)
+            .mirroring(Mirroring::VERTICAL)
+            .build();
+
+        let rom: Rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 2);
+        assert_eq!(rom.mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.prg.len(), 3 * PRG_PAGE_SIZE);
+        assert_eq!(rom.prg[PRG_PAGE_SIZE], 7);
+        assert_eq!(rom.prg[3 * PRG_PAGE_SIZE - 1], 0);
+        assert!(rom.chr.is_empty());
+    }
+
+    #[test_case(0x42, Mirroring::FOUR_SCREEN)]
+    #[test_case(0xF1, Mirroring::HORIZONTAL)]
+    fn test_rom_builder_header(mapper: u8, mirroring: Mirroring) {
+        let rom: Rom = Rom::new(&RomBuilder::new().mapper(mapper).mirroring(mirroring).build()).unwrap();
+        assert_eq!(rom.mapper, mapper);
+        assert_eq!(rom.mirroring, mirroring);
+    }
+
+    #[test_case(0b0000_0000, false)]
+    #[test_case(0b0000_0010, true)]
+    #[test_case(0b1111_0011, true)]
+    fn test_parse_battery(flags6: u8, expected: bool) {
+        assert_eq!(parse_battery(&header(flags6, 0, 1, 1)), expected);
+    }
+
+    #[test]
+    fn test_rom_builder_battery() {
+        let rom: Rom = Rom::new(&RomBuilder::new().battery(true).mapper(1).build()).unwrap();
+        assert!(rom.has_battery);
+        assert_eq!(rom.mapper, 1);
+        assert!(!test_rom().has_battery);
+    }
+
+    #[test]
+    fn test_truncated_data_err() {
+        let mut raw: Vec<u8> = header(0, 0, 2, 1);
+        raw.extend(vec![0; PRG_PAGE_SIZE]);
+        let rom: Result<Rom, String> = Rom::new(&raw);
+        assert_eq!(rom.err().unwrap(), "ROM data is truncated.");
+    }
+}
@@ -0,0 +1,122 @@
+// Runner for the SingleStepTests 6502 JSON suite. Each case gives the CPU
+// and RAM state before and after one instruction, plus the bus cycles it
+// takes.
+use crate::bus::Bus;
+use crate::cpu::{CPUFlags, CPU};
+use crate::mem::Mem;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct CpuState {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+#[derive(Deserialize)]
+struct TestCase {
+    name: String,
+    initial: CpuState,
+    #[serde(rename = "final")]
+    expected: CpuState,
+    cycles: Vec<(u16, u8, String)>,
+}
+
+fn load_state(state: &CpuState) -> CPU {
+    let mut cpu: CPU = CPU::new(Bus::flat());
+    cpu.program_counter = state.pc;
+    cpu.stack_ptr = state.s;
+    cpu.accumulator = state.a;
+    cpu.register_x = state.x;
+    cpu.register_y = state.y;
+    cpu.status = CPUFlags::from_bits_truncate(state.p);
+    for (addr, value) in state.ram.iter() {
+        cpu.mem_write(*addr, *value);
+    }
+    cpu
+}
+
+fn check(name: &str, field: &str, actual: u16, expected: u16) -> Result<(), String> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("{}: {} is {:04X}, expected {:04X}", name, field, actual, expected))
+    }
+}
+
+fn run_case(case: &TestCase) -> Result<(), String> {
+    let mut cpu: CPU = load_state(&case.initial);
+    let start: usize = cpu.bus.cycles();
+    cpu.step();
+
+    let expected: &CpuState = &case.expected;
+    check(&case.name, "pc", cpu.program_counter, expected.pc)?;
+    check(&case.name, "s", cpu.stack_ptr as u16, expected.s as u16)?;
+    check(&case.name, "a", cpu.accumulator as u16, expected.a as u16)?;
+    check(&case.name, "x", cpu.register_x as u16, expected.x as u16)?;
+    check(&case.name, "y", cpu.register_y as u16, expected.y as u16)?;
+    check(&case.name, "p", cpu.status.bits() as u16, expected.p as u16)?;
+    for (addr, value) in expected.ram.iter() {
+        check(&case.name, &format!("ram[{:04X}]", addr), cpu.peek(*addr) as u16, *value as u16)?;
+    }
+    check(&case.name, "cycles", (cpu.bus.cycles() - start) as u16, case.cycles.len() as u16)
+}
+
+// Run every case in a JSON file, returning a message for each failure
+pub fn run_file(json: &str) -> Result<Vec<String>, String> {
+    let cases: Vec<TestCase> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    Ok(cases.iter().filter_map(|case| run_case(case).err()).collect())
+}
+
+const SAMPLE_CASES: &str = r#"[
+    {
+        "name": "a9 80 ea",
+        "initial": { "pc": 4096, "s": 253, "a": 0, "x": 0, "y": 0, "p": 34,
+                     "ram": [[4096, 169], [4097, 128], [4098, 234]] },
+        "final": { "pc": 4098, "s": 253, "a": 128, "x": 0, "y": 0, "p": 160,
+                   "ram": [[4096, 169], [4097, 128], [4098, 234]] },
+        "cycles": [[4096, 169, "read"], [4097, 128, "read"]]
+    },
+    {
+        "name": "69 50 ea",
+        "initial": { "pc": 8192, "s": 253, "a": 80, "x": 0, "y": 0, "p": 32,
+                     "ram": [[8192, 105], [8193, 80], [8194, 234]] },
+        "final": { "pc": 8194, "s": 253, "a": 160, "x": 0, "y": 0, "p": 224,
+                   "ram": [[8192, 105], [8193, 80]] },
+        "cycles": [[8192, 105, "read"], [8193, 80, "read"]]
+    },
+    {
+        "name": "8d 34 12",
+        "initial": { "pc": 12288, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36,
+                     "ram": [[12288, 141], [12289, 52], [12290, 18], [4660, 0]] },
+        "final": { "pc": 12291, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36,
+                   "ram": [[4660, 66]] },
+        "cycles": [[12288, 141, "read"], [12289, 52, "read"], [12290, 18, "read"], [4660, 66, "write"]]
+    },
+    {
+        "name": "20 00 50",
+        "initial": { "pc": 16384, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                     "ram": [[16384, 32], [16385, 0], [16386, 80]] },
+        "final": { "pc": 20480, "s": 251, "a": 0, "x": 0, "y": 0, "p": 36,
+                   "ram": [[509, 64], [508, 2]] },
+        "cycles": [[16384, 32, "read"], [16385, 0, "read"], [509, 0, "read"],
+                   [509, 64, "write"], [508, 2, "write"], [16386, 80, "read"]]
+    }
+]"#;
+
+#[test]
+fn test_sample_cases() {
+    let failures: Vec<String> = run_file(SAMPLE_CASES).unwrap();
+    assert!(failures.is_empty(), "{:?}", failures);
+}
+
+#[test]
+fn test_mismatch_reported() {
+    let broken: String = SAMPLE_CASES.replacen("\"a\": 128", "\"a\": 127", 1);
+    let failures: Vec<String> = run_file(&broken).unwrap();
+    assert_eq!(failures, vec!["a9 80 ea: a is 0080, expected 007F".to_string()]);
+}
@@ -8,20 +8,17 @@ pub fn trace(cpu: &CPU) -> String {
     let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
 
     // Get current opcode and program counter
-    let code: u8 = cpu.mem_read(cpu.program_counter);
+    let code: u8 = cpu.peek(cpu.program_counter);
     let curr_op: &&opcodes::OpCode = opscodes.get(&code).unwrap();
     let begin: u16 = cpu.program_counter;
-
-    // Create hex dump and add current opcode
-    let mut hex_dump: Vec<u8> = vec![];
-    hex_dump.push(code);
+    let (bytes, len): ([u8; 3], u8) = cpu.instruction_bytes(begin);
 
     // Get address and value addressed to for operation
     let (mem_addr, stored_value): (u16, u8) = match curr_op.mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
         _ => {
             let addr: u16 = cpu.get_non_immediate_addr(&curr_op.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            (addr, cpu.peek(addr))
         }
     };
 
@@ -32,8 +29,7 @@ pub fn trace(cpu: &CPU) -> String {
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            hex_dump.push(address);
+            let address: u8 = bytes[1];
 
             match curr_op.mode {
                 AddressingMode::Immediate => format!("#${:02x}", address),
@@ -73,23 +69,18 @@ pub fn trace(cpu: &CPU) -> String {
             }
         }
         3 => {
-            let address_lo: u8 = cpu.mem_read(begin + 1);
-            let address_hi: u8 = cpu.mem_read(begin + 2);
-            hex_dump.push(address_lo);
-            hex_dump.push(address_hi);
-
-            let address: u16 = cpu.mem_read_u16(begin + 1);
+            let address: u16 = u16::from_le_bytes([bytes[1], bytes[2]]);
 
             match curr_op.mode {
                 AddressingMode::NoneAddressing => {
                     // Handle jump operation
                     if curr_op.code == 0x6c {
                         let jmp_addr: u16 = if address & 0x00FF == 0x00FF {
-                            let lo: u8 = cpu.mem_read(address);
-                            let hi: u8 = cpu.mem_read(address & 0xFF00);
+                            let lo: u8 = cpu.peek(address);
+                            let hi: u8 = cpu.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            cpu.peek_u16(address)
                         };
 
                         format!("(${:04x}) = {:04x}", address, jmp_addr)
@@ -115,7 +106,7 @@ pub fn trace(cpu: &CPU) -> String {
         _ => String::from(""),
     };
 
-    let hex_str: String = hex_dump
+    let hex_str: String = bytes[..len as usize]
         .iter()
         .map(|z| format!("{:02x}", z))
         .collect::<Vec<String>>()
@@ -131,11 +122,48 @@ pub fn trace(cpu: &CPU) -> String {
     .to_ascii_uppercase()
 }
 
+// Plain assembler syntax for the instruction at `addr`, without the
+// resolved addresses and values trace() shows
+pub fn disassemble(cpu: &CPU, addr: u16) -> String {
+    let (bytes, _): ([u8; 3], u8) = cpu.instruction_bytes(addr);
+    let code: u8 = bytes[0];
+    let op: &opcodes::OpCode = match opcodes::OPCODE_TABLE[code as usize] {
+        Some(op) => op,
+        None => return format!(".DB ${:02X}", code),
+    };
+    let byte: u8 = bytes[1];
+    let word: u16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+
+    let operand: String = match (&op.mode, op.len) {
+        _ if code == 0x6C => format!("(${:04X})", word),
+        _ if matches!(code, 0x0A | 0x2A | 0x4A | 0x6A) => String::from("A"),
+        (AddressingMode::Immediate, _) => format!("#${:02X}", byte),
+        (AddressingMode::ZeroPage, _) => format!("${:02X}", byte),
+        (AddressingMode::ZeroPage_X, _) => format!("${:02X},X", byte),
+        (AddressingMode::ZeroPage_Y, _) => format!("${:02X},Y", byte),
+        (AddressingMode::Absolute, _) => format!("${:04X}", word),
+        (AddressingMode::Absolute_X, _) => format!("${:04X},X", word),
+        (AddressingMode::Absolute_Y, _) => format!("${:04X},Y", word),
+        (AddressingMode::Indirect_X, _) => format!("(${:02X},X)", byte),
+        (AddressingMode::Indirect_Y, _) => format!("(${:02X}),Y", byte),
+        (AddressingMode::NoneAddressing, 3) => format!("${:04X}", word),
+        // Branches, shown as their target
+        (AddressingMode::NoneAddressing, 2) => {
+            let target: u16 = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("${:04X}", target)
+        }
+        _ => String::new(),
+    };
+
+    format!("{} {}", op.operation, operand).trim().to_string()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::bus::Bus;
     use crate::rom::test::test_rom;
+    use test_case::test_case;
 
     #[test]
     fn test_format_trace() {
@@ -195,4 +223,20 @@ mod test {
             result[0]
         );
     }
-}
\ No newline at end of file
+
+    #[test_case(&[0xA9, 0x42], "LDA #$42")]
+    #[test_case(&[0xBD, 0x34, 0x12], "LDA $1234,X")]
+    #[test_case(&[0x91, 0x20], "STA ($20),Y")]
+    #[test_case(&[0x6C, 0xFF, 0x02], "JMP ($02FF)")]
+    #[test_case(&[0xD0, 0xFD], "BNE $0063")]
+    #[test_case(&[0x4A], "LSR A")]
+    #[test_case(&[0xE8], "INX")]
+    #[test_case(&[0x02], "KIL")]
+    fn test_disassemble(program: &[u8], expected: &str) {
+        let mut cpu: CPU = CPU::new(Bus::new(test_rom()));
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mem_write(0x64 + i as u16, *byte);
+        }
+        assert_eq!(disassemble(&cpu, 0x64), expected);
+    }
+}